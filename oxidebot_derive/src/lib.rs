@@ -0,0 +1,390 @@
+//! Derive macros for [oxidebot](https://github.com/canxin121/oxidebot), enable them with its `derive` feature.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input, spanned::Spanned, Data, DeriveInput, Fields, GenericArgument, LitChar,
+    LitStr, PathArguments, Type,
+};
+
+/// Derive `oxidebot::command::CommandArgs` for a struct with named fields.
+///
+/// - `bool` fields are flags: `--name`, plus `-c` with `#[arg(short = 'c')]`; mark a field with `#[arg(flag)]` when its type
+///   is an alias of `bool`
+/// - fields with `#[arg(long)]`, `#[arg(long = "name")]` or `#[arg(short = 'c')]` are options taking a value
+/// - the other fields are positional in order: `Option<T>` ones are optional and a `Vec<T>` one, the last of them, takes the rest
+/// - `#[arg(default = "value")]` gives the value of a missing argument
+/// - `#[arg(name = "value")]` is the name of the value shown in the usage
+///
+/// The field types implement `oxidebot::command::FromCommandArg`, e.g. all the `FromStr` types, `At` and `Image`.
+#[proc_macro_derive(CommandArgs, attributes(arg))]
+pub fn derive_command_args(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+enum Kind {
+    Flag,
+    Named,
+    Positional,
+}
+
+struct Arg {
+    ident: syn::Ident,
+    ty: Type,
+    kind: Kind,
+    /// `--name` and `-c`
+    names: Vec<String>,
+    /// The name of the value shown in the usage
+    value_name: String,
+    default: Option<String>,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new(
+            input.span(),
+            "CommandArgs can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new(
+            input.span(),
+            "CommandArgs can only be derived for structs with named fields",
+        ));
+    };
+    let args = fields
+        .named
+        .iter()
+        .map(parse_field)
+        .collect::<syn::Result<Vec<_>>>()?;
+    let positionals = args
+        .iter()
+        .filter(|arg| matches!(arg.kind, Kind::Positional))
+        .collect::<Vec<_>>();
+    if let Some(arg) = positionals
+        .iter()
+        .rev()
+        .skip(1)
+        .find(|arg| wrapped(&arg.ty, "Vec").is_some())
+    {
+        return Err(syn::Error::new(
+            arg.ty.span(),
+            "a `Vec` field takes the rest of the arguments, it must be the last positional field",
+        ));
+    }
+
+    let usage = args.iter().map(usage).collect::<Vec<_>>().join(" ");
+    let flags = args
+        .iter()
+        .filter(|arg| matches!(arg.kind, Kind::Flag))
+        .flat_map(|arg| arg.names.iter());
+    let options = args
+        .iter()
+        .filter(|arg| matches!(arg.kind, Kind::Named))
+        .flat_map(|arg| arg.names.iter());
+    let parse = args.iter().map(parse_arg);
+    let idents = args.iter().map(|arg| &arg.ident);
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::oxidebot::command::CommandArgs for #name #ty_generics #where_clause {
+            fn parse_args(
+                args: &[::oxidebot::command::CommandArg],
+            ) -> ::std::result::Result<Self, ::oxidebot::command::CommandArgsError> {
+                #[allow(unused_mut)]
+                let mut parser = ::oxidebot::command::ArgParser::new(
+                    args,
+                    &[#(#flags),*],
+                    &[#(#options),*],
+                    <Self as ::oxidebot::command::CommandArgs>::usage(),
+                )?;
+                #(#parse)*
+                parser.finish()?;
+                ::std::result::Result::Ok(#name { #(#idents),* })
+            }
+
+            fn usage() -> ::std::string::String {
+                ::std::string::String::from(#usage)
+            }
+        }
+    })
+}
+
+fn parse_field(field: &syn::Field) -> syn::Result<Arg> {
+    let ident = field.ident.clone().expect("named field");
+    let field_name = ident.to_string().trim_start_matches("r#").to_string();
+    let mut long = None;
+    let mut short = None;
+    let mut value_name = None;
+    let mut default = None;
+    let mut flag = false;
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("arg"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("long") {
+                long = Some(match meta.value() {
+                    Ok(value) => value.parse::<LitStr>()?.value(),
+                    Err(_) => field_name.replace('_', "-"),
+                });
+            } else if meta.path.is_ident("short") {
+                short = Some(meta.value()?.parse::<LitChar>()?.value());
+            } else if meta.path.is_ident("name") {
+                value_name = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("default") {
+                default = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("flag") {
+                flag = true;
+            } else {
+                return Err(meta.error("expected `long`, `short`, `name`, `default` or `flag`"));
+            }
+            Ok(())
+        })?;
+    }
+
+    let is_bool = flag || is_bool(&field.ty);
+    let kind = if is_bool {
+        long.get_or_insert_with(|| field_name.replace('_', "-"));
+        Kind::Flag
+    } else if long.is_some() || short.is_some() {
+        Kind::Named
+    } else {
+        Kind::Positional
+    };
+    if default.is_some() && (is_bool || wrapped(&field.ty, "Option").is_some()) {
+        return Err(syn::Error::new(
+            field.span(),
+            "`default` can't be used with `bool` or `Option` fields",
+        ));
+    }
+    if wrapped(&field.ty, "Vec").is_some()
+        && (!matches!(kind, Kind::Positional) || default.is_some())
+    {
+        return Err(syn::Error::new(
+            field.ty.span(),
+            "a `Vec` field takes the rest of the positional arguments, it can't be a flag, an option or have a `default`",
+        ));
+    }
+
+    let names = long
+        .map(|long| format!("--{}", long))
+        .into_iter()
+        .chain(short.map(|short| format!("-{}", short)))
+        .collect();
+    Ok(Arg {
+        ident,
+        ty: field.ty.clone(),
+        kind,
+        names,
+        value_name: value_name.unwrap_or(field_name),
+        default,
+    })
+}
+
+/// Whether the type is `bool`, also written as a path like `std::primitive::bool`
+fn is_bool(ty: &Type) -> bool {
+    let Type::Path(path) = ty else {
+        return false;
+    };
+    path.qself.is_none()
+        && path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "bool" && segment.arguments.is_none())
+}
+
+/// Get `T` of `Option<T>` or `Vec<T>`
+fn wrapped<'a>(ty: &'a Type, wrapper: &str) -> Option<&'a Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != wrapper {
+        return None;
+    }
+    let PathArguments::AngleBracketed(arguments) = &segment.arguments else {
+        return None;
+    };
+    match arguments.args.first()? {
+        GenericArgument::Type(ty) => Some(ty),
+        _ => None,
+    }
+}
+
+fn usage(arg: &Arg) -> String {
+    let name = &arg.value_name;
+    let optional = arg.default.is_some() || wrapped(&arg.ty, "Option").is_some();
+    match arg.kind {
+        Kind::Flag => format!("[{}]", arg.names[0]),
+        Kind::Named if optional => format!("[{} <{}>]", arg.names[0], name),
+        Kind::Named => format!("{} <{}>", arg.names[0], name),
+        Kind::Positional if wrapped(&arg.ty, "Vec").is_some() => format!("[{}...]", name),
+        Kind::Positional if optional => format!("[{}]", name),
+        Kind::Positional => format!("<{}>", name),
+    }
+}
+
+fn parse_arg(arg: &Arg) -> TokenStream2 {
+    let Arg {
+        ident,
+        ty,
+        names,
+        value_name,
+        default,
+        ..
+    } = arg;
+    let value = match (&arg.kind, wrapped(ty, "Option"), wrapped(ty, "Vec")) {
+        (Kind::Flag, _, _) => quote!(parser.flag(&[#(#names),*])),
+        (Kind::Named, Some(inner), _) => {
+            quote!(parser.option::<#inner>(&[#(#names),*], #value_name)?)
+        }
+        (Kind::Named, None, _) => {
+            let missing = match default {
+                Some(default) => quote!(parser.default::<#ty>(#value_name, #default)?),
+                None => {
+                    let option = &names[0];
+                    quote!(return ::std::result::Result::Err(parser.missing(#option)))
+                }
+            };
+            quote! {
+                match parser.option::<#ty>(&[#(#names),*], #value_name)? {
+                    ::std::option::Option::Some(value) => value,
+                    ::std::option::Option::None => #missing,
+                }
+            }
+        }
+        (Kind::Positional, Some(inner), _) => quote!(parser.optional::<#inner>(#value_name)?),
+        (Kind::Positional, None, Some(inner)) => quote!(parser.rest::<#inner>(#value_name)?),
+        (Kind::Positional, None, None) => match default {
+            Some(default) => quote! {
+                match parser.optional::<#ty>(#value_name)? {
+                    ::std::option::Option::Some(value) => value,
+                    ::std::option::Option::None => parser.default::<#ty>(#value_name, #default)?,
+                }
+            },
+            None => quote!(parser.positional::<#ty>(#value_name)?),
+        },
+    };
+    quote!(let #ident = #value;)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    fn args(input: DeriveInput) -> syn::Result<Vec<Arg>> {
+        let Data::Struct(data) = &input.data else {
+            unreachable!()
+        };
+        data.fields.iter().map(parse_field).collect()
+    }
+
+    fn error(input: DeriveInput) -> String {
+        expand(input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn bool_paths_are_flags() {
+        let args = args(parse_quote! {
+            struct Args {
+                a: bool,
+                b: std::primitive::bool,
+                c: ::core::primitive::bool,
+                #[arg(flag)]
+                d: Flag,
+                e: Option<bool>,
+            }
+        })
+        .unwrap();
+        let flags = args
+            .iter()
+            .filter(|arg| matches!(arg.kind, Kind::Flag))
+            .map(|arg| arg.names[0].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(flags, ["--a", "--b", "--c", "--d"]);
+    }
+
+    #[test]
+    fn usage_lists_the_fields_in_order() {
+        let args = args(parse_quote! {
+            struct Args {
+                city: String,
+                #[arg(name = "n")]
+                days: Option<u8>,
+                #[arg(long, short = 'u')]
+                unit_name: String,
+                #[arg(short = 'l', default = "en")]
+                lang: String,
+                dry_run: bool,
+                rest: Vec<String>,
+            }
+        })
+        .unwrap();
+        let usage = args.iter().map(usage).collect::<Vec<_>>().join(" ");
+        assert_eq!(
+            usage,
+            "<city> [n] --unit-name <unit_name> [-l <lang>] [--dry-run] [rest...]"
+        );
+    }
+
+    #[test]
+    fn misused_vec_fields_are_rejected() {
+        let message = error(parse_quote! {
+            struct Args {
+                #[arg(long)]
+                tags: Vec<String>,
+            }
+        });
+        assert!(
+            message.contains("can't be a flag, an option"),
+            "{}",
+            message
+        );
+        let message = error(parse_quote! {
+            struct Args {
+                tags: Vec<String>,
+                city: String,
+            }
+        });
+        assert!(
+            message.contains("must be the last positional field"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn invalid_attributes_are_rejected() {
+        let message = error(parse_quote! {
+            struct Args {
+                #[arg(default = "1")]
+                count: Option<u8>,
+            }
+        });
+        assert!(message.contains("`default` can't be used"), "{}", message);
+        let message = error(parse_quote! {
+            struct Args {
+                #[arg(positional)]
+                count: u8,
+            }
+        });
+        assert!(message.contains("expected `long`"), "{}", message);
+        let message = error(parse_quote! {
+            enum Args {}
+        });
+        assert!(
+            message.contains("only be derived for structs"),
+            "{}",
+            message
+        );
+    }
+}
@@ -0,0 +1,9 @@
+use oxidebot::command::CommandArgs;
+
+#[derive(CommandArgs)]
+struct Count {
+    #[arg(default = "1")]
+    count: Option<u8>,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use oxidebot::command::CommandArgs;
+
+#[derive(CommandArgs)]
+struct Count {
+    #[arg(positional)]
+    count: u8,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use oxidebot::command::CommandArgs;
+
+#[derive(CommandArgs)]
+struct Tags {
+    tags: Vec<String>,
+    city: String,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use oxidebot::command::CommandArgs;
+
+#[derive(CommandArgs)]
+struct Tags {
+    #[arg(long)]
+    tags: Vec<String>,
+}
+
+fn main() {}
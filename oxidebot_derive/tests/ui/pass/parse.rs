@@ -0,0 +1,39 @@
+use oxidebot::command::{CommandArg, CommandArgs};
+
+type Flag = bool;
+
+#[derive(CommandArgs, Debug, PartialEq)]
+struct Ban {
+    user: String,
+    #[arg(long, short = 'm', default = "60")]
+    minutes: u32,
+    quiet: std::primitive::bool,
+    #[arg(flag)]
+    silent: Flag,
+    reason: Vec<String>,
+}
+
+fn args(text: &str) -> Vec<CommandArg> {
+    text.split_whitespace()
+        .map(|arg| CommandArg::Text(arg.to_string()))
+        .collect()
+}
+
+fn main() {
+    assert_eq!(
+        Ban::usage(),
+        "<user> [--minutes <minutes>] [--quiet] [--silent] [reason...]"
+    );
+    assert_eq!(
+        Ban::parse_args(&args("alice -m 5 --quiet spam again")).unwrap(),
+        Ban {
+            user: "alice".to_string(),
+            minutes: 5,
+            quiet: true,
+            silent: false,
+            reason: vec!["spam".to_string(), "again".to_string()],
+        }
+    );
+    assert_eq!(Ban::parse_args(&args("bob --silent")).unwrap().minutes, 60);
+    assert!(Ban::parse_args(&args("")).is_err());
+}
@@ -0,0 +1,522 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::{
+    command::{Command, CommandArg, CommandContext},
+    filter::FilterTrait,
+    help::Permission,
+    matcher::Matcher,
+    source::message::MessageSegment,
+    utils::storage::JsonStorage,
+};
+
+/// AccessTarget is a user or group that can be blocked or allowed
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AccessTarget {
+    User { server: String, id: String },
+    Group { server: String, id: String },
+}
+
+impl AccessTarget {
+    pub fn user<S: Into<String>, T: Into<String>>(server: S, id: T) -> Self {
+        AccessTarget::User {
+            server: server.into(),
+            id: id.into(),
+        }
+    }
+
+    pub fn group<S: Into<String>, T: Into<String>>(server: S, id: T) -> Self {
+        AccessTarget::Group {
+            server: server.into(),
+            id: id.into(),
+        }
+    }
+
+    /// Get the user and the group of the event
+    pub fn of(matcher: &Matcher) -> Vec<Self> {
+        let server = matcher.bot.server();
+        let user = matcher
+            .try_get_user()
+            .map(|user| Self::user(server, &user.id));
+        let group = matcher
+            .try_get_group()
+            .map(|group| Self::group(server, &group.id));
+        user.into_iter().chain(group).collect()
+    }
+
+    fn to_json(&self, expires: Option<SystemTime>) -> Value {
+        let mut value = match self {
+            AccessTarget::User { server, id } => json!({ "user": id, "server": server }),
+            AccessTarget::Group { server, id } => json!({ "group": id, "server": server }),
+        };
+        if let Some(expires) = expires.and_then(|expires| expires.duration_since(UNIX_EPOCH).ok()) {
+            value["expires"] = json!(expires.as_secs());
+        }
+        value
+    }
+
+    fn from_json(value: &Value) -> Option<(Self, Option<SystemTime>)> {
+        let server = value.get("server")?.as_str()?;
+        let field = |key: &str| value.get(key)?.as_str();
+        let target = if let Some(id) = field("user") {
+            Self::user(server, id)
+        } else {
+            Self::group(server, field("group")?)
+        };
+        let expires = value
+            .get("expires")
+            .and_then(Value::as_u64)
+            .and_then(|secs| UNIX_EPOCH.checked_add(Duration::from_secs(secs)));
+        Some((target, expires))
+    }
+}
+
+impl std::fmt::Display for AccessTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccessTarget::User { server, id } => write!(f, "user {} on {}", id, server),
+            AccessTarget::Group { server, id } => write!(f, "group {} on {}", id, server),
+        }
+    }
+}
+
+/// The targets of a list with their expiry times
+type Entries = HashMap<AccessTarget, Option<SystemTime>>;
+
+fn is_listed(entries: &Entries, target: &AccessTarget, now: SystemTime) -> bool {
+    entries
+        .get(target)
+        .is_some_and(|expires| expires.map_or(true, |expires| expires > now))
+}
+
+/// The time `duration` from now, None for a duration too long to be represented, i.e. forever
+fn expiry(duration: Option<Duration>) -> Option<SystemTime> {
+    duration.and_then(|duration| SystemTime::now().checked_add(duration))
+}
+
+#[derive(Default)]
+struct AccessState {
+    blocked: Entries,
+    allowed: Entries,
+    allow_only: bool,
+    /// The `(server, id)` of the superusers given to `access_command`, who are never filtered out
+    superusers: Vec<(String, String)>,
+}
+
+/// AccessList is a built-in filter dropping the events of the blocked users and groups,
+/// and, in allow-only mode, the events of the users and groups that are not allowed.
+/// The entries can expire, and are persisted to a JSON file with `storage`.
+/// It's a costless cloneable handle, so the entries can be managed at runtime, or with `access_command`,
+/// whose superusers always pass so they can't lock themselves out.
+#[derive(Clone)]
+pub struct AccessList {
+    state: Arc<Mutex<AccessState>>,
+    storage: JsonStorage,
+    priority: u8,
+}
+
+impl Default for AccessList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccessList {
+    pub fn new() -> Self {
+        AccessList {
+            state: Arc::default(),
+            storage: JsonStorage::default(),
+            priority: 8,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, AccessState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Only let the events of the allowed users and groups pass, the events of neither a user nor a group always pass
+    pub fn allow_only(self, allow_only: bool) -> Self {
+        self.lock().allow_only = allow_only;
+        self
+    }
+
+    /// Set the priority of the filter, 8 by default so it runs before the other filters
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Persist the entries to the JSON file at `path`, and load the ones it already contains
+    pub async fn storage<P: Into<PathBuf>>(self, path: P) -> Self {
+        if let Some(value) = self.storage.open(path.into(), "access list storage").await {
+            let entries = |key: &str| {
+                value
+                    .get(key)
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(AccessTarget::from_json)
+                    .collect::<Vec<_>>()
+            };
+            let mut state = self.lock();
+            state.blocked.extend(entries("blocked"));
+            state.allowed.extend(entries("allowed"));
+        }
+        self
+    }
+
+    /// Whether the event passes, i.e. its user is a superuser of `access_command`,
+    /// or neither its user nor its group is blocked and in allow-only mode, either of them is allowed
+    pub fn permits(&self, matcher: &Matcher) -> bool {
+        let targets = AccessTarget::of(matcher);
+        let now = SystemTime::now();
+        let state = self.lock();
+        let server = matcher.bot.server();
+        if matcher.try_get_user().is_some_and(|user| {
+            state
+                .superusers
+                .iter()
+                .any(|superuser| superuser.0 == server && superuser.1 == user.id)
+        }) {
+            return true;
+        }
+        if targets
+            .iter()
+            .any(|target| is_listed(&state.blocked, target, now))
+        {
+            return false;
+        }
+        !state.allow_only
+            || targets.is_empty()
+            || targets
+                .iter()
+                .any(|target| is_listed(&state.allowed, target, now))
+    }
+
+    pub fn is_blocked(&self, target: &AccessTarget) -> bool {
+        is_listed(&self.lock().blocked, target, SystemTime::now())
+    }
+
+    pub fn is_allowed(&self, target: &AccessTarget) -> bool {
+        is_listed(&self.lock().allowed, target, SystemTime::now())
+    }
+
+    /// List the blocked targets with their expiry times
+    pub fn blocked(&self) -> Vec<(AccessTarget, Option<SystemTime>)> {
+        Self::list(&self.lock().blocked)
+    }
+
+    /// List the allowed targets with their expiry times
+    pub fn allowed(&self) -> Vec<(AccessTarget, Option<SystemTime>)> {
+        Self::list(&self.lock().allowed)
+    }
+
+    fn list(entries: &Entries) -> Vec<(AccessTarget, Option<SystemTime>)> {
+        let now = SystemTime::now();
+        entries
+            .iter()
+            .filter(|(target, _)| is_listed(entries, target, now))
+            .map(|(target, expires)| (target.clone(), *expires))
+            .collect()
+    }
+
+    /// Block the target, for `duration` or forever
+    pub async fn block(&self, target: AccessTarget, duration: Option<Duration>) -> Result<()> {
+        let expires = expiry(duration);
+        self.update(|state| {
+            state.blocked.insert(target, expires);
+        })
+        .await
+    }
+
+    pub async fn unblock(&self, target: &AccessTarget) -> Result<()> {
+        self.update(|state| {
+            state.blocked.remove(target);
+        })
+        .await
+    }
+
+    /// Allow the target, for `duration` or forever
+    pub async fn allow(&self, target: AccessTarget, duration: Option<Duration>) -> Result<()> {
+        let expires = expiry(duration);
+        self.update(|state| {
+            state.allowed.insert(target, expires);
+        })
+        .await
+    }
+
+    pub async fn disallow(&self, target: &AccessTarget) -> Result<()> {
+        self.update(|state| {
+            state.allowed.remove(target);
+        })
+        .await
+    }
+
+    async fn update(&self, change: impl FnOnce(&mut AccessState)) -> Result<()> {
+        self.storage
+            .update(
+                || {
+                    let mut state = self.lock();
+                    change(&mut state);
+                    let now = SystemTime::now();
+                    state
+                        .blocked
+                        .retain(|_, expires| expires.map_or(true, |expires| expires > now));
+                    state
+                        .allowed
+                        .retain(|_, expires| expires.map_or(true, |expires| expires > now));
+                    Ok(())
+                },
+                || {
+                    let state = self.lock();
+                    let entries = |entries: &Entries| {
+                        entries
+                            .iter()
+                            .map(|(target, expires)| target.to_json(*expires))
+                            .collect::<Vec<_>>()
+                    };
+                    json!({
+                        "blocked": entries(&state.blocked),
+                        "allowed": entries(&state.allowed),
+                    })
+                },
+            )
+            .await
+    }
+}
+
+#[async_trait]
+impl FilterTrait for AccessList {
+    async fn filter(&self, matcher: Matcher) -> bool {
+        self.permits(&matcher)
+    }
+
+    fn get_priority(&self) -> u8 {
+        self.priority
+    }
+}
+
+/// Create the `access` command for the superusers, given as `(server, id)` pairs, to block, unblock, allow and disallow users and groups of the current server,
+/// e.g. `access block user @someone 1d`, `access unblock group 12345` or `access list`.
+/// The list always lets the events of the superusers pass, even in allow-only mode.
+pub fn access_command(list: AccessList, superusers: Vec<(String, String)>) -> Command {
+    list.lock().superusers.extend(superusers.iter().cloned());
+    let subcommand = |name: &str, description: &str, change: Change| {
+        let list = list.clone();
+        let mut command = Command::new(name)
+            .description(description)
+            .min_args(2)
+            .max_args(2);
+        if matches!(change, Change::Block | Change::Allow) {
+            command = command
+                .usage("<user | group> <id> [duration]")
+                .example(format!("{} user @someone 1d", name))
+                .max_args(3);
+        } else {
+            command = command.usage("<user | group> <id>");
+        }
+        command.handler(move |ctx: CommandContext| {
+            let list = list.clone();
+            async move { change_access(ctx, list, change).await }
+        })
+    };
+    let list_command = {
+        let list = list.clone();
+        Command::new("list")
+            .description("List the blocked and allowed users and groups")
+            .max_args(0)
+            .handler(move |ctx: CommandContext| {
+                let list = list.clone();
+                async move { reply_access(ctx, list).await }
+            })
+    };
+    Command::new("access")
+        .description("Manage the blocked and allowed users and groups")
+        .permission(Permission::Users(superusers))
+        .subcommand(subcommand("block", "Block a user or group", Change::Block))
+        .subcommand(subcommand(
+            "unblock",
+            "Unblock a user or group",
+            Change::Unblock,
+        ))
+        .subcommand(subcommand("allow", "Allow a user or group", Change::Allow))
+        .subcommand(subcommand(
+            "disallow",
+            "Disallow a user or group",
+            Change::Disallow,
+        ))
+        .subcommand(list_command)
+}
+
+#[derive(Clone, Copy)]
+enum Change {
+    Block,
+    Unblock,
+    Allow,
+    Disallow,
+}
+
+async fn change_access(ctx: CommandContext, list: AccessList, change: Change) -> Result<()> {
+    let server = ctx.matcher.bot.server();
+    let id = match ctx.args.get(1) {
+        Some(CommandArg::Text(id)) => id.clone(),
+        Some(CommandArg::Segment(MessageSegment::At { user_id })) => user_id.clone(),
+        _ => return ctx.reply("Expected an id or a mention").await,
+    };
+    let target = match ctx.args[0].as_text() {
+        Some("user") => AccessTarget::user(server, id),
+        Some("group") => AccessTarget::group(server, id),
+        _ => return ctx.reply("Expected user or group").await,
+    };
+    let duration = match ctx.args.get(2) {
+        Some(arg) => match arg.as_text().and_then(parse_duration) {
+            Some(duration) => Some(duration),
+            None => return ctx.reply("Invalid duration, e.g. 30s, 10m, 2h or 7d").await,
+        },
+        None => None,
+    };
+    let (result, done) = match change {
+        Change::Block => (list.block(target.clone(), duration).await, "blocked"),
+        Change::Unblock => (list.unblock(&target).await, "unblocked"),
+        Change::Allow => (list.allow(target.clone(), duration).await, "allowed"),
+        Change::Disallow => (list.disallow(&target).await, "disallowed"),
+    };
+    match result {
+        Ok(()) => match duration {
+            Some(duration) => {
+                ctx.reply(format!(
+                    "The {} is {} for {}s",
+                    target,
+                    done,
+                    duration.as_secs()
+                ))
+                .await
+            }
+            None => ctx.reply(format!("The {} is {}", target, done)).await,
+        },
+        Err(e) => {
+            ctx.reply(format!("Failed to save the access list: {}", e))
+                .await
+        }
+    }
+}
+
+async fn reply_access(ctx: CommandContext, list: AccessList) -> Result<()> {
+    let now = SystemTime::now();
+    let mut text = String::new();
+    for (title, entries) in [("Blocked:", list.blocked()), ("Allowed:", list.allowed())] {
+        text.push_str(title);
+        if entries.is_empty() {
+            text.push_str("\nNone");
+        }
+        for (target, expires) in entries {
+            text.push_str(&format!("\n{}", target));
+            if let Some(left) = expires.and_then(|expires| expires.duration_since(now).ok()) {
+                text.push_str(&format!(" ({}s left)", left.as_secs()));
+            }
+        }
+        text.push('\n');
+    }
+    ctx.reply(text.trim_end().to_string()).await
+}
+
+/// Parse a duration like `30s`, `10m`, `2h` or `7d`
+fn parse_duration(text: &str) -> Option<Duration> {
+    let unit = match text.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let value = text[..text.len() - 1].parse::<u64>().ok()?;
+    Some(Duration::from_secs(value.checked_mul(unit)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::message;
+
+    #[tokio::test]
+    async fn entries_expire() {
+        let list = AccessList::new();
+        let user = AccessTarget::user("test", "1");
+        list.block(user.clone(), Some(Duration::from_secs(3600)))
+            .await
+            .unwrap();
+        assert!(list.is_blocked(&user));
+        assert!(!list.permits(&message(Some("g"), "1", "hi")));
+
+        list.block(user.clone(), Some(Duration::ZERO))
+            .await
+            .unwrap();
+        assert!(!list.is_blocked(&user));
+        assert!(list.blocked().is_empty());
+        assert!(list.permits(&message(Some("g"), "1", "hi")));
+
+        // too long to be represented, so forever
+        list.block(user.clone(), Some(Duration::MAX)).await.unwrap();
+        assert_eq!(list.blocked(), [(user.clone(), None)]);
+        list.allow(
+            user.clone(),
+            Some(parse_duration("213503982334601d").unwrap()),
+        )
+        .await
+        .unwrap();
+        assert!(list.is_allowed(&user));
+    }
+
+    #[tokio::test]
+    async fn allow_only_lets_the_allowed_and_the_superusers_pass() {
+        let list = AccessList::new().allow_only(true);
+        list.allow(AccessTarget::group("test", "g"), None)
+            .await
+            .unwrap();
+        assert!(list.permits(&message(Some("g"), "1", "hi")));
+        assert!(!list.permits(&message(Some("other"), "1", "hi")));
+        assert!(!list.permits(&message(None, "1", "hi")));
+
+        list.block(AccessTarget::user("test", "1"), None)
+            .await
+            .unwrap();
+        assert!(!list.permits(&message(Some("g"), "1", "hi")));
+
+        let _command = access_command(list.clone(), vec![("test".to_string(), "1".to_string())]);
+        assert!(list.permits(&message(None, "1", "hi")));
+        assert!(!list.permits(&message(None, "2", "hi")));
+    }
+
+    #[tokio::test]
+    async fn entries_are_reloaded_from_the_storage() {
+        let dir = std::env::temp_dir().join(format!("oxidebot-access-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("access.json");
+        let _ = tokio::fs::remove_file(&path).await;
+
+        let list = AccessList::new().storage(&path).await;
+        let user = AccessTarget::user("test", "1");
+        let group = AccessTarget::group("test", "g");
+        list.block(user.clone(), Some(Duration::from_secs(3600)))
+            .await
+            .unwrap();
+        list.allow(group.clone(), None).await.unwrap();
+        list.allow(AccessTarget::user("test", "2"), Some(Duration::ZERO))
+            .await
+            .unwrap();
+
+        let reloaded = AccessList::new().storage(&path).await;
+        assert!(reloaded.is_blocked(&user));
+        assert!(reloaded.blocked()[0].1.is_some());
+        assert_eq!(reloaded.allowed(), [(group, None)]);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -3,6 +3,7 @@ pub enum GroupMuteType {
     Unmute,
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum SendMessageTarget {
     Group(String),
     Private(String),
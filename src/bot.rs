@@ -1,9 +1,18 @@
 use std::any::Any;
-use std::sync::LazyLock;
-use tokio::sync::{broadcast, RwLock};
-use tokio::task::JoinHandle;
+use std::future::Future;
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, RwLock};
+use tokio::task::{JoinHandle, JoinSet};
 
-use crate::{api::CallApiTrait, matcher::Matcher, source::bot::BotInfo};
+use crate::{
+    api::CallApiTrait,
+    channel::EventSender,
+    event::{meta::MetaEventObject, MetaEvent},
+    matcher::Matcher,
+    source::bot::BotInfo,
+    supervisor::{BackoffPolicy, Restarts},
+};
 
 pub type BotObject = Box<dyn BotTrait>;
 /// Bot should impl CallApiTrait before impl BotTrait
@@ -14,13 +23,21 @@ pub trait BotTrait: Send + Sync + CallApiTrait {
     /// get the basic information of the bot
     async fn bot_info(&self) -> BotInfo;
     /// start_sending_events is a async function that will be `tokio::spawn` called when the bot is started
-    async fn start_sending_events(&self, sender: broadcast::Sender<Matcher>);
+    /// Send the events with `EventSender::send`, it may wait when the OxideBotManager runs in `ChannelMode::Lossless`
+    /// Call `EventSender::connected` once connected to the server, otherwise the first event sent reports the connection
+    async fn start_sending_events(&self, sender: EventSender);
     /// server means the server that the bot belongs to
     fn server(&self) -> &'static str;
     /// TraitObject can't inherit Clone, so you should manually implement it
     fn clone_box(&self) -> BotObject;
     /// TraitObject can't downcast to the concrete type, so you should implement it manually
     fn as_any(&self) -> &dyn Any;
+    /// The bot wrapped by the Interceptors of an OxideBotManager, so it's never wrapped twice.
+    /// Only the wrapper implements it, don't override it.
+    #[doc(hidden)]
+    fn unintercepted(&self) -> Option<BotObject> {
+        None
+    }
 }
 
 impl Clone for BotObject {
@@ -35,38 +52,471 @@ impl std::fmt::Debug for BotObject {
     }
 }
 
-static GLOBAL_BOTS: LazyLock<RwLock<Vec<BotObject>>> = LazyLock::new(|| RwLock::new(Vec::new()));
-static GLOBAL_HANDLERS: LazyLock<RwLock<Vec<JoinHandle<()>>>> =
-    LazyLock::new(|| RwLock::new(Vec::new()));
+/// BotState is the state of the `start_sending_events` task of a bot
+#[derive(Clone, Debug, PartialEq)]
+pub enum BotState {
+    /// The task is running, but the bot didn't report being connected yet
+    Connecting,
+    /// The task is running, and the bot reported being connected with `EventSender::connected` or by sending an event
+    Connected,
+    /// The task stopped, and will be restarted after the backoff delay
+    Reconnecting { attempt: u32 },
+    /// The task stopped and the BackoffPolicy gave up restarting it
+    Stopped,
+}
+
+/// How long a bot whose `bot_info` returned no id is left alone before asking again
+const ID_RETRY_INTERVAL: Duration = Duration::from_secs(30);
 
-pub(crate) async fn add_bots(bots: Vec<BotObject>, sender: broadcast::Sender<Matcher>) {
-    let mut bots_lock = GLOBAL_BOTS.write().await;
-    let mut handlers_lock = GLOBAL_HANDLERS.write().await;
+/// The id of a bot, so `bot_info` isn't called for every lookup
+#[derive(Debug, Default)]
+struct CachedId {
+    id: Option<String>,
+    /// When `bot_info` was last called, while the id is unknown
+    asked: Option<Instant>,
+}
+
+/// BotId is the cached id of a bot, shared by its entry in the BotRegistry and the events it sends
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BotId(Arc<Mutex<CachedId>>);
+
+impl BotId {
+    /// A BotId already known, e.g. the one of a bot elected by the Dedup middleware
+    pub(crate) fn known(id: String) -> Self {
+        BotId(Arc::new(Mutex::new(CachedId {
+            id: Some(id),
+            asked: None,
+        })))
+    }
+
+    /// Get the id of the bot, `bot_info` is called until it returns one, at most once every `ID_RETRY_INTERVAL`
+    pub(crate) async fn get(&self, bot: &BotObject) -> Option<String> {
+        {
+            let mut cached = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            if cached.id.is_some()
+                || cached
+                    .asked
+                    .is_some_and(|asked| asked.elapsed() < ID_RETRY_INTERVAL)
+            {
+                return cached.id.clone();
+            }
+            // the concurrent lookups don't ask the bot again meanwhile
+            cached.asked = Some(Instant::now());
+        }
+        let id = bot.bot_info().await.id;
+        if id.is_none() {
+            tracing::warn!(
+                "Bot on {} has no id, retrying in {:?}",
+                bot.server(),
+                ID_RETRY_INTERVAL
+            );
+        }
+        let mut cached = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        cached.id.clone_from(&id);
+        id
+    }
+}
+
+struct BotEntry {
+    bot: BotObject,
+    task: JoinHandle<()>,
+    state: watch::Receiver<BotState>,
+    id: BotId,
+}
+
+impl BotEntry {
+    async fn id(&self) -> Option<String> {
+        self.id.get(&self.bot).await
+    }
+}
+
+struct RegistryInner {
+    entries: RwLock<Vec<Arc<BotEntry>>>,
+    sender: EventSender,
+    backoff: Mutex<BackoffPolicy>,
+}
+
+/// Registries of all the alive OxideBotManagers, only used by `get_bot` outside of a manager's task.
+static LIVE_REGISTRIES: LazyLock<Mutex<Vec<Weak<RegistryInner>>>> =
+    LazyLock::new(|| Mutex::new(Vec::new()));
+
+tokio::task_local! {
+    static CURRENT_REGISTRY: BotRegistry;
+}
+
+/// BotRegistry keeps the bots registed in an OxideBotManager and the tasks running their `start_sending_events`.
+/// It's a costless cloneable handle, so handlers can hold it to look up, add or remove bots at runtime.
+#[derive(Clone)]
+pub struct BotRegistry {
+    inner: Arc<RegistryInner>,
+}
+
+impl BotRegistry {
+    pub(crate) fn new(sender: EventSender) -> Self {
+        let inner = Arc::new(RegistryInner {
+            entries: RwLock::new(Vec::new()),
+            sender,
+            backoff: Mutex::new(BackoffPolicy::default()),
+        });
+        let mut live = LIVE_REGISTRIES.lock().unwrap_or_else(|e| e.into_inner());
+        live.retain(|registry| registry.strong_count() > 0);
+        live.push(Arc::downgrade(&inner));
+        BotRegistry { inner }
+    }
+
+    /// Set the BackoffPolicy used by the bots added after this call with `add_bot`
+    pub fn set_backoff_policy(&self, backoff: BackoffPolicy) {
+        *self.inner.backoff.lock().unwrap_or_else(|e| e.into_inner()) = backoff;
+    }
+
+    /// Add a bot and start its `start_sending_events` task.
+    /// It can be called at any time, even when the OxideBotManager is running.
+    /// The task is supervised, and restarted with the BackoffPolicy set by `set_backoff_policy` when it stops.
+    pub async fn add_bot(&self, bot: BotObject) {
+        let backoff = self
+            .inner
+            .backoff
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        self.add_bot_with_backoff(bot, backoff).await;
+    }
+
+    /// Add a bot like `add_bot`, but restart its task with the given BackoffPolicy.
+    pub async fn add_bot_with_backoff(&self, bot: BotObject, backoff: BackoffPolicy) {
+        let (state_sender, state) = watch::channel(BotState::Connecting);
+        let id = BotId::default();
+        let task = tokio::spawn(supervise(
+            bot.clone(),
+            id.clone(),
+            self.inner.sender.clone(),
+            backoff,
+            state_sender,
+        ));
+
+        self.inner.entries.write().await.push(Arc::new(BotEntry {
+            bot,
+            task,
+            state,
+            id,
+        }));
+    }
+
+    /// Get the entries without holding the lock, so the bots can be called
+    async fn entries(&self) -> Vec<Arc<BotEntry>> {
+        self.inner.entries.read().await.clone()
+    }
+
+    /// Find the entry of the bot by server and bot_id
+    async fn find(&self, server: &str, bot_id: &str) -> Option<Arc<BotEntry>> {
+        for entry in self.entries().await {
+            if server == entry.bot.server() && entry.id().await.as_deref() == Some(bot_id) {
+                return Some(entry);
+            }
+        }
+        None
+    }
+
+    /// Remove the bot by server and bot_id, its `start_sending_events` task will be aborted.
+    /// Return the removed bot, or None if no such bot is registed.
+    pub async fn remove_bot(&self, server: &str, bot_id: &str) -> Option<BotObject> {
+        let entry = self.find(server, bot_id).await?;
+        let mut entries = self.inner.entries.write().await;
+        let index = entries
+            .iter()
+            .position(|other| Arc::ptr_eq(other, &entry))?;
+        entries.remove(index);
+        entry.task.abort();
+        Some(entry.bot.clone())
+    }
+
+    /// Abort the `start_sending_events` tasks of all bots and clear the registry.
+    pub(crate) async fn abort_all(&self) {
+        let mut entries = self.inner.entries.write().await;
+
+        for entry in entries.drain(..) {
+            entry.task.abort();
+        }
+    }
+
+    /// Run the future with this registry as the one used by `get_bot`.
+    pub(crate) async fn scope<F: Future>(&self, future: F) -> F::Output {
+        CURRENT_REGISTRY.scope(self.clone(), future).await
+    }
 
-    for bot in bots {
+    /// Get bot registed in this registry by server and bot_id
+    pub async fn get_bot(&self, server: &str, bot_id: &str) -> Option<BotObject> {
+        let entry = self.find(server, bot_id).await?;
+        Some(self.inner.sender.intercept(entry.bot.clone()))
+    }
+
+    /// List all bots registed in this registry
+    pub async fn list_bots(&self) -> Vec<BotObject> {
+        let entries = self.inner.entries.read().await;
+        entries
+            .iter()
+            .map(|entry| self.inner.sender.intercept(entry.bot.clone()))
+            .collect()
+    }
+
+    /// Get the current BotState of the bot by server and bot_id
+    pub async fn bot_state(&self, server: &str, bot_id: &str) -> Option<BotState> {
+        let entry = self.find(server, bot_id).await?;
+        let state = entry.state.borrow().clone();
+        Some(state)
+    }
+
+    /// Whether the user is one of the bots registed in this registry, e.g. to ignore the messages sent by the bots.
+    /// The ids of the bots are cached, so `bot_info` is only called until it returns the id, and not on every call.
+    pub async fn is_bot_user(&self, server: &str, user_id: &str) -> bool {
+        self.find(server, user_id).await.is_some()
+    }
+
+    /// List all bots registed in this registry with their current BotState
+    pub async fn list_bot_states(&self) -> Vec<(BotObject, BotState)> {
+        let entries = self.inner.entries.read().await;
+        entries
+            .iter()
+            .map(|entry| {
+                let bot = self.inner.sender.intercept(entry.bot.clone());
+                (bot, entry.state.borrow().clone())
+            })
+            .collect()
+    }
+}
+
+/// Connection reports the BotState of a supervised bot through the EventSender given to its `start_sending_events`
+#[derive(Clone, Debug)]
+pub(crate) struct Connection {
+    bot: BotObject,
+    id: BotId,
+    state: Arc<watch::Sender<BotState>>,
+}
+
+impl Connection {
+    /// Mark the bot as connected, return true if it wasn't yet since the task (re)started
+    pub(crate) fn connect(&self) -> bool {
+        self.state.send_if_modified(|state| {
+            let connecting = *state != BotState::Connected;
+            *state = BotState::Connected;
+            connecting
+        })
+    }
+
+    pub(crate) fn bot(&self) -> &BotObject {
+        &self.bot
+    }
+
+    pub(crate) fn id(&self) -> &BotId {
+        &self.id
+    }
+}
+
+/// Run `start_sending_events` of the bot, and restart it with the BackoffPolicy whenever it returns or panics.
+/// A ConnectEvent is sent once the bot reported being connected, and a DisconnectEvent when the task stops.
+async fn supervise(
+    bot: BotObject,
+    id: BotId,
+    sender: EventSender,
+    backoff: BackoffPolicy,
+    state: watch::Sender<BotState>,
+) {
+    let state = Arc::new(state);
+    let mut restarts = Restarts::default();
+    loop {
+        let started = Instant::now();
+        // the JoinSet aborts the event task when this supervisor is aborted
+        let mut event_task = JoinSet::new();
         let bot_ = bot.clone();
-        let sender = sender.clone();
-        let handler = tokio::spawn(async move {
-            bot_.start_sending_events(sender.clone()).await;
+        let sender_ = sender.with_connection(Connection {
+            bot: bot.clone(),
+            id: id.clone(),
+            state: state.clone(),
         });
+        event_task.spawn(async move { bot_.start_sending_events(sender_).await });
+
+        let bot_id = match event_task.join_next().await {
+            Some(Err(e)) if e.is_panic() => {
+                let bot_id = id.get(&bot).await;
+                tracing::error!(
+                    "Event task of bot {:?} on {} panicked: {:?}",
+                    bot_id,
+                    bot.server(),
+                    e
+                );
+                bot_id
+            }
+            _ => {
+                let bot_id = id.get(&bot).await;
+                tracing::warn!("Event task of bot {:?} on {} stopped", bot_id, bot.server());
+                bot_id
+            }
+        };
+        send_meta_event(&bot, &sender, MetaEvent::DisconnectEvent).await;
+
+        let Some((attempt, delay)) = restarts.next(&backoff, started) else {
+            tracing::error!(
+                "Event task of bot {:?} on {} stopped too many times, giving up",
+                bot_id,
+                bot.server()
+            );
+            state.send_replace(BotState::Stopped);
+            return;
+        };
 
-        bots_lock.push(bot);
-        handlers_lock.push(handler);
+        state.send_replace(BotState::Reconnecting { attempt });
+        tracing::info!(
+            "Restarting event task of bot {:?} on {} in {:?} (attempt {})",
+            bot_id,
+            bot.server(),
+            delay,
+            attempt
+        );
+        tokio::time::sleep(delay).await;
+        state.send_replace(BotState::Connecting);
     }
 }
 
+async fn send_meta_event(bot: &BotObject, sender: &EventSender, event: MetaEvent) {
+    let event_object = Box::new(MetaEventObject {
+        server: bot.server(),
+        event,
+    });
+    if let Err(e) = sender
+        .send_all(Matcher::new(event_object, bot.clone()))
+        .await
+    {
+        tracing::warn!("Failed to send meta event: {:?}", e);
+    }
+}
+
+/// Get the registry of the OxideBotManager running the current task, if any.
+pub(crate) fn current_registry() -> Option<BotRegistry> {
+    CURRENT_REGISTRY.try_with(|registry| registry.clone()).ok()
+}
+
+/// Run the future with the registry as the one used by `get_bot`, or just run it if there is no registry.
+pub(crate) async fn scope_registry<F: Future>(
+    bot_registry: Option<BotRegistry>,
+    future: F,
+) -> F::Output {
+    match bot_registry {
+        Some(bot_registry) => bot_registry.scope(future).await,
+        None => future.await,
+    }
+}
+
+/// Spawn a task that keeps the registry of the current task, so `get_bot` in the task still searches
+/// the bots of the OxideBotManager running the handler that spawned it. Use it instead of `tokio::spawn` in handlers.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(scope_registry(current_registry(), future))
+}
+
 /// Get bot registed in OxideBotManager by server and bot_id
+///
+/// Inside the handlers and filters run by an OxideBotManager, and the tasks they started with `bot::spawn`,
+/// only the bots of that manager are searched.
+/// Elsewhere, the bots are only searched when a single OxideBotManager is alive, so the bots of a manager never leak to another;
+/// prefer holding a `BotRegistry` from `OxideBotManager::bot_registry` in that case.
 pub async fn get_bot(server: &str, bot_id: &str) -> Option<BotObject> {
-    let bots = GLOBAL_BOTS.read().await;
-    for bot in bots.iter() {
-        if server != bot.server() {
-            continue;
-        }
-        if let Some(bot_info) = bot.bot_info().await.id {
-            if bot_info == bot_id {
-                return Some(bot.clone());
+    if let Some(registry) = current_registry() {
+        return registry.get_bot(server, bot_id).await;
+    }
+
+    let registry = {
+        let mut live = LIVE_REGISTRIES.lock().unwrap_or_else(|e| e.into_inner());
+        live.retain(|registry| registry.strong_count() > 0);
+        match live.as_slice() {
+            [registry] => registry.upgrade().map(|inner| BotRegistry { inner }),
+            [] => None,
+            _ => {
+                tracing::warn!(
+                    "get_bot was called outside of an OxideBotManager while {} are alive, use a BotRegistry or bot::spawn instead",
+                    live.len()
+                );
+                None
             }
         }
+    };
+    registry?.get_bot(server, bot_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        manager::OxideBotManager,
+        testing::{message_to, Recorder, TestBot},
+    };
+
+    async fn id_of(bot: Option<BotObject>) -> Option<String> {
+        bot?.bot_info().await.id
+    }
+
+    #[tokio::test]
+    async fn managers_dont_see_the_bots_of_each_other() {
+        let first = OxideBotManager::new()
+            .bot(Box::new(TestBot::new("first")))
+            .await;
+        let second = OxideBotManager::new()
+            .bot(Box::new(TestBot::new("second")))
+            .await;
+        let (first, second) = (first.bot_registry(), second.bot_registry());
+
+        let found = first
+            .scope(async {
+                (
+                    id_of(get_bot("test", "first").await).await,
+                    get_bot("test", "second").await.is_none(),
+                )
+            })
+            .await;
+        assert_eq!(found, (Some("first".to_string()), true));
+        // the tasks spawned with bot::spawn keep the registry of their manager
+        let found = second
+            .scope(async {
+                spawn(async { id_of(get_bot("test", "second").await).await })
+                    .await
+                    .unwrap()
+            })
+            .await;
+        assert_eq!(found.as_deref(), Some("second"));
+        assert!(spawn(async { get_bot("test", "first").await })
+            .await
+            .unwrap()
+            .is_none());
+        // outside of both managers, it's ambiguous which one to search
+        assert!(get_bot("test", "first").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn bots_are_added_and_removed_while_running() {
+        let recorder = Recorder::default();
+        let manager = OxideBotManager::new().handler(recorder.handler());
+        let registry = manager.bot_registry();
+        let running = tokio::spawn(manager.run_until(std::future::pending::<()>(), Duration::ZERO));
+
+        let bot = TestBot::new("late");
+        registry.add_bot(Box::new(bot.clone())).await;
+        bot.sender()
+            .await
+            .send(message_to(&bot, Some("g"), "1", "hello"))
+            .await
+            .unwrap();
+        recorder.wait(1).await;
+        assert!(recorder.texts().contains(&"hello".to_string()));
+        bot.wait_running(1).await;
+
+        let removed = registry.remove_bot("test", "late").await;
+        assert_eq!(id_of(removed).await.as_deref(), Some("late"));
+        bot.wait_running(0).await;
+        assert!(registry.get_bot("test", "late").await.is_none());
+        running.abort();
     }
-    None
 }
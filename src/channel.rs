@@ -0,0 +1,331 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use anyhow::Result;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    bot::{BotObject, Connection},
+    event::{meta::MetaEventObject, Event, MetaEvent},
+    extensions::Extensions,
+    intercept::Interceptors,
+    matcher::Matcher,
+};
+
+/// ChannelMode decides how the events are delivered from the bots to the OxideBotManager
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum ChannelMode {
+    /// Events are sent through a broadcast channel, `EventSender::send` never waits,
+    /// and the oldest events are dropped when the OxideBotManager can't keep up.
+    #[default]
+    Broadcast,
+    /// Events are sent through a bounded mpsc channel, `EventSender::send` waits while the channel is full,
+    /// so no event is dropped before being handled.
+    Lossless,
+}
+
+/// ChannelConfig configures the event channel of an OxideBotManager
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelConfig {
+    /// The number of events the channel can hold
+    pub capacity: usize,
+    pub mode: ChannelMode,
+    /// Send a `MetaEvent::LaggedEvent` to the handlers when events were dropped
+    pub lag_event: bool,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            capacity: 100,
+            mode: ChannelMode::Broadcast,
+            lag_event: false,
+        }
+    }
+}
+
+/// EventSender is given to `BotTrait::start_sending_events` to send the events to the OxideBotManager.
+#[derive(Clone, Debug)]
+pub struct EventSender {
+    inner: EventSenderInner,
+    extensions: Extensions,
+    interceptors: Interceptors,
+    /// Set for the EventSender given to the `start_sending_events` of a bot in the BotRegistry
+    connection: Option<Connection>,
+}
+
+#[derive(Clone, Debug)]
+enum EventSenderInner {
+    Broadcast(broadcast::Sender<Matcher>),
+    Lossless(mpsc::Sender<Matcher>),
+}
+
+impl EventSender {
+    /// Send the matcher to the OxideBotManager.
+    /// In `ChannelMode::Lossless`, this waits until there is room in the channel.
+    /// The first event sent reports the bot as connected, see `connected`.
+    pub async fn send(&self, matcher: Matcher) -> Result<()> {
+        let is_connect_event = matches!(
+            matcher.event.as_ref(),
+            Event::MetaEvent(MetaEvent::ConnectEvent)
+        );
+        if let Some(connection) = &self.connection {
+            // a bot sending its own ConnectEvent doesn't get a second one
+            if connection.connect() && !is_connect_event {
+                self.send_connect_event(connection).await?;
+            }
+        }
+        self.deliver(matcher).await
+    }
+
+    /// Report that the bot is connected, e.g. once it logged in to the server, before it has any event to send.
+    /// The `BotState` becomes `Connected` and a `MetaEvent::ConnectEvent` is sent,
+    /// only once after each (re)start of `start_sending_events`.
+    pub async fn connected(&self) -> Result<()> {
+        match &self.connection {
+            Some(connection) if connection.connect() => self.send_connect_event(connection).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn send_connect_event(&self, connection: &Connection) -> Result<()> {
+        let event_object = Box::new(MetaEventObject {
+            server: connection.bot().server(),
+            event: MetaEvent::ConnectEvent,
+        });
+        for matcher in Matcher::new(event_object, connection.bot().clone()) {
+            self.deliver(matcher).await?;
+        }
+        Ok(())
+    }
+
+    /// Get an EventSender reporting the BotState of a supervised bot
+    pub(crate) fn with_connection(&self, connection: Connection) -> Self {
+        EventSender {
+            connection: Some(connection),
+            ..self.clone()
+        }
+    }
+
+    async fn deliver(&self, mut matcher: Matcher) -> Result<()> {
+        matcher.extensions = self.extensions.clone();
+        if let Some(connection) = &self.connection {
+            matcher.bot_id = Some(connection.id().clone());
+        }
+        matcher.bot = self.intercept(matcher.bot);
+        match &self.inner {
+            EventSenderInner::Broadcast(sender) => {
+                sender
+                    .send(matcher)
+                    .map_err(|_| anyhow::anyhow!("OxideBotManager stopped"))?;
+            }
+            EventSenderInner::Lossless(sender) => {
+                sender
+                    .send(matcher)
+                    .await
+                    .map_err(|_| anyhow::anyhow!("OxideBotManager stopped"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wrap the bot so its messages pass through the interceptors of the OxideBotManager
+    pub(crate) fn intercept(&self, bot: BotObject) -> BotObject {
+        self.interceptors.wrap(bot)
+    }
+
+    /// Send all the matchers in order, usually the ones created by `Matcher::new`
+    pub async fn send_all(&self, matchers: Vec<Matcher>) -> Result<()> {
+        for matcher in matchers {
+            self.send(matcher).await?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) enum EventReceiver {
+    Broadcast(broadcast::Receiver<Matcher>),
+    Lossless(mpsc::Receiver<Matcher>),
+}
+
+pub(crate) enum Received {
+    Matcher(Matcher),
+    Lagged(u64),
+    Closed,
+}
+
+impl EventReceiver {
+    pub(crate) async fn recv(&mut self) -> Received {
+        match self {
+            EventReceiver::Broadcast(receiver) => match receiver.recv().await {
+                Ok(matcher) => Received::Matcher(matcher),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => Received::Lagged(skipped),
+                Err(broadcast::error::RecvError::Closed) => Received::Closed,
+            },
+            EventReceiver::Lossless(receiver) => match receiver.recv().await {
+                Some(matcher) => Received::Matcher(matcher),
+                None => Received::Closed,
+            },
+        }
+    }
+}
+
+/// Create the event channel, the broadcast sender is always returned so the waiters can subscribe to it.
+/// The matchers sent through the EventSender share the extensions, and their bots pass through the interceptors.
+pub(crate) fn event_channel(
+    config: &ChannelConfig,
+    extensions: Extensions,
+    interceptors: Interceptors,
+) -> (EventSender, EventReceiver, broadcast::Sender<Matcher>) {
+    let capacity = config.capacity.max(1);
+    match config.mode {
+        ChannelMode::Broadcast => {
+            let (sender, receiver) = broadcast::channel(capacity);
+            (
+                EventSender {
+                    inner: EventSenderInner::Broadcast(sender.clone()),
+                    extensions,
+                    interceptors,
+                    connection: None,
+                },
+                EventReceiver::Broadcast(receiver),
+                sender,
+            )
+        }
+        ChannelMode::Lossless => {
+            let (sender, receiver) = mpsc::channel(capacity);
+            let (broadcast_sender, _) = broadcast::channel(capacity);
+            (
+                EventSender {
+                    inner: EventSenderInner::Lossless(sender),
+                    extensions,
+                    interceptors,
+                    connection: None,
+                },
+                EventReceiver::Lossless(receiver),
+                broadcast_sender,
+            )
+        }
+    }
+}
+
+/// ChannelStats counts the events dropped by the event channel of an OxideBotManager.
+/// It's a costless cloneable handle.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelStats {
+    dropped_events: Arc<AtomicU64>,
+}
+
+impl ChannelStats {
+    pub(crate) fn add_dropped_events(&self, count: u64) {
+        self.dropped_events.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Get the number of events dropped because the OxideBotManager couldn't keep up
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        manager::OxideBotManager,
+        testing::{message_to, Recorder, TestBot},
+    };
+
+    #[tokio::test]
+    async fn dropped_events_are_counted_and_reported() {
+        let bot = TestBot::new("bot");
+        let recorder = Recorder::default();
+        let manager = OxideBotManager::with_channel(ChannelConfig {
+            capacity: 2,
+            mode: ChannelMode::Broadcast,
+            lag_event: true,
+        })
+        .bot(Box::new(bot.clone()))
+        .await
+        .handler(recorder.handler());
+        let stats = manager.channel_stats();
+
+        // the ConnectEvent and the first 3 messages are dropped before the manager runs
+        let sender = bot.sender().await;
+        for text in ["0", "1", "2", "3", "4"] {
+            sender
+                .send(message_to(&bot, Some("g"), "1", text))
+                .await
+                .unwrap();
+        }
+        let handled = recorder.clone();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            manager.run_until(async move { handled.wait(3).await }, Duration::from_secs(1)),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(stats.dropped_events(), 4);
+        let mut texts = recorder.texts();
+        texts.sort();
+        assert_eq!(texts, ["3", "4"]);
+        assert!(recorder
+            .handled
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|matcher| matches!(
+                matcher.event.as_ref(),
+                Event::MetaEvent(MetaEvent::LaggedEvent { skipped: 4 })
+            )));
+    }
+
+    #[tokio::test]
+    async fn lossless_mode_waits_instead_of_dropping() {
+        let bot = TestBot::new("bot");
+        let recorder = Recorder::default();
+        let manager = OxideBotManager::with_channel(ChannelConfig {
+            capacity: 1,
+            mode: ChannelMode::Lossless,
+            lag_event: true,
+        })
+        .bot(Box::new(bot.clone()))
+        .await
+        .handler(recorder.handler());
+        let stats = manager.channel_stats();
+
+        // the ConnectEvent fills the channel, so the message waits for the manager to run
+        let sender = bot.sender().await;
+        sender.connected().await.unwrap();
+        let waiting = tokio::time::timeout(
+            Duration::from_millis(50),
+            sender.send(message_to(&bot, Some("g"), "1", "lost")),
+        )
+        .await;
+        assert!(waiting.is_err());
+
+        let sending = tokio::spawn(async move {
+            for text in ["0", "1", "2", "3", "4"] {
+                sender
+                    .send(message_to(&bot, Some("g"), "1", text))
+                    .await
+                    .unwrap();
+            }
+        });
+        let handled = recorder.clone();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            manager.run_until(async move { handled.wait(6).await }, Duration::from_secs(1)),
+        )
+        .await
+        .unwrap();
+        sending.await.unwrap();
+
+        assert_eq!(stats.dropped_events(), 0);
+        assert_eq!(recorder.texts(), ["0", "1", "2", "3", "4"]);
+    }
+}
@@ -0,0 +1,850 @@
+use std::{collections::VecDeque, future::Future, str::FromStr, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{
+    handler::{EventHandlerTrait, Handler, Propagation, DEFAULT_PRIORITY},
+    help::{HandlerMetadata, Permission},
+    matcher::Matcher,
+    source::message::{File, Message, MessageSegment},
+};
+
+#[cfg(feature = "derive")]
+pub use oxidebot_derive::CommandArgs;
+
+/// CommandPrefix marks a message as a command
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandPrefix {
+    /// The message starts with the text, e.g. `/` or `!`
+    Text(String),
+    /// The message starts by mentioning the bot
+    Mention,
+}
+
+impl CommandPrefix {
+    pub fn text<T: Into<String>>(prefix: T) -> Self {
+        CommandPrefix::Text(prefix.into())
+    }
+}
+
+/// CommandArg is an argument of a command
+#[derive(Clone, Debug, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+pub enum CommandArg {
+    /// A word of the text, or a quoted text with its quotes removed
+    Text(String),
+    /// A segment that isn't text, e.g. an image or a mention
+    Segment(MessageSegment),
+}
+
+impl CommandArg {
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            CommandArg::Text(text) => Some(text),
+            CommandArg::Segment(_) => None,
+        }
+    }
+
+    pub fn as_segment(&self) -> Option<&MessageSegment> {
+        match self {
+            CommandArg::Text(_) => None,
+            CommandArg::Segment(segment) => Some(segment),
+        }
+    }
+}
+
+/// CommandContext is given to the CommandHandler of the invoked command
+#[derive(Clone, Debug)]
+pub struct CommandContext {
+    pub matcher: Matcher,
+    /// The prefix that the command was invoked with, empty for a mention
+    pub prefix: String,
+    /// The names of the invoked command and its subcommands, aliases are resolved to the names
+    pub command: Vec<String>,
+    pub args: Vec<CommandArg>,
+}
+
+impl CommandContext {
+    /// Get the text arguments, the other segments are skipped
+    pub fn text_args(&self) -> Vec<&str> {
+        self.args.iter().filter_map(CommandArg::as_text).collect()
+    }
+
+    /// Parse the argument at `index`, None if it's missing, not a text or can't be parsed
+    pub fn arg<T: FromStr>(&self, index: usize) -> Option<T> {
+        self.args.get(index)?.as_text()?.parse().ok()
+    }
+
+    /// Parse the arguments into a CommandArgs struct, the error is displayed with the usage
+    pub fn parse<T: CommandArgs>(&self) -> Result<T, CommandArgsError> {
+        T::parse_args(&self.args)
+    }
+
+    /// Reply a text to the message of the command
+    pub async fn reply<T: Into<String>>(&self, text: T) -> Result<()> {
+        self.matcher
+            .try_send_message(vec![MessageSegment::text(text)])
+            .await
+            .map(|_| ())
+    }
+}
+
+/// Command handler runs a command
+#[async_trait]
+pub trait CommandHandlerTrait: Send + Sync {
+    async fn run(&self, context: CommandContext) -> Result<()>;
+}
+
+/// Any `Fn(CommandContext) -> impl Future<Output = Result<()>>` closure is a command handler
+#[async_trait]
+impl<F, Fut> CommandHandlerTrait for F
+where
+    F: Fn(CommandContext) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    async fn run(&self, context: CommandContext) -> Result<()> {
+        self(context).await
+    }
+}
+
+/// Command is a named command with aliases, arguments and subcommands
+pub struct Command {
+    name: String,
+    aliases: Vec<String>,
+    description: Option<String>,
+    usage: Option<String>,
+    examples: Vec<String>,
+    permission: Permission,
+    min_args: usize,
+    max_args: Option<usize>,
+    handler: Option<Arc<dyn CommandHandlerTrait>>,
+    subcommands: Vec<Command>,
+}
+
+impl Command {
+    pub fn new<T: Into<String>>(name: T) -> Self {
+        Command {
+            name: name.into(),
+            aliases: Vec::new(),
+            description: None,
+            usage: None,
+            examples: Vec::new(),
+            permission: Permission::Everyone,
+            min_args: 0,
+            max_args: None,
+            handler: None,
+            subcommands: Vec::new(),
+        }
+    }
+
+    /// Add another name of the command
+    pub fn alias<T: Into<String>>(mut self, alias: T) -> Self {
+        self.aliases.push(alias.into());
+        self
+    }
+
+    /// Set the description shown in the usage of the parent command
+    pub fn description<T: Into<String>>(mut self, description: T) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Set the usage of the arguments, e.g. `<city> [days]`
+    pub fn usage<T: Into<String>>(mut self, usage: T) -> Self {
+        self.usage = Some(usage.into());
+        self
+    }
+
+    /// Add an example shown by the help command, e.g. `/weather London`
+    pub fn example<T: Into<String>>(mut self, example: T) -> Self {
+        self.examples.push(example.into());
+        self
+    }
+
+    /// Set who may run the command and its subcommands
+    pub fn permission(mut self, permission: Permission) -> Self {
+        self.permission = permission;
+        self
+    }
+
+    /// Set the minimum number of arguments, the usage is replied when fewer are given
+    pub fn min_args(mut self, min_args: usize) -> Self {
+        self.min_args = min_args;
+        self
+    }
+
+    /// Set the maximum number of arguments, the usage is replied when more are given
+    pub fn max_args(mut self, max_args: usize) -> Self {
+        self.max_args = Some(max_args);
+        self
+    }
+
+    pub fn handler<H: CommandHandlerTrait + 'static>(mut self, handler: H) -> Self {
+        self.handler = Some(Arc::new(handler));
+        self
+    }
+
+    pub fn subcommand(mut self, subcommand: Command) -> Self {
+        self.subcommands.push(subcommand);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn aliases(&self) -> &[String] {
+        &self.aliases
+    }
+
+    pub fn get_description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub fn subcommands(&self) -> &[Command] {
+        &self.subcommands
+    }
+
+    /// Collect the HandlerMetadata of the command and its subcommands that have a handler
+    fn collect_metadata(
+        &self,
+        invocation: &str,
+        permission: &Permission,
+        metadata: &mut Vec<HandlerMetadata>,
+    ) {
+        let invocation = format!("{}{}", invocation, self.name);
+        // a subcommand can only be run by who may run its parent
+        let permission = &permission.and(&self.permission);
+        if self.handler.is_some() {
+            metadata.push(HandlerMetadata {
+                name: invocation.clone(),
+                description: self.description.clone(),
+                usage: Some(match &self.usage {
+                    Some(usage) => format!("{} {}", invocation, usage),
+                    None => invocation.clone(),
+                }),
+                examples: self.examples.clone(),
+                permission: permission.clone(),
+                plugin: None,
+            });
+        }
+        for subcommand in &self.subcommands {
+            subcommand.collect_metadata(&format!("{} ", invocation), permission, metadata);
+        }
+    }
+
+    fn is_called(&self, name: &str) -> bool {
+        self.name == name || self.aliases.iter().any(|alias| alias == name)
+    }
+
+    fn accepts(&self, args: usize) -> bool {
+        args >= self.min_args && self.max_args.map_or(true, |max_args| args <= max_args)
+    }
+
+    /// The usage of the command invoked as `invocation`, with its subcommands
+    fn usage_text(&self, invocation: &str) -> String {
+        let mut text = match &self.usage {
+            Some(usage) => format!("Usage: {} {}", invocation, usage),
+            None => format!("Usage: {}", invocation),
+        };
+        if let Some(description) = &self.description {
+            text.push_str(&format!("\n{}", description));
+        }
+        if !self.subcommands.is_empty() {
+            text.push_str("\nSubcommands:");
+            for subcommand in &self.subcommands {
+                match &subcommand.description {
+                    Some(description) => {
+                        text.push_str(&format!("\n  {} - {}", subcommand.name, description))
+                    }
+                    None => text.push_str(&format!("\n  {}", subcommand.name)),
+                }
+            }
+        }
+        text
+    }
+}
+
+/// CommandRouter is an event handler running the commands of the messages starting with one of its prefixes.
+/// A message invoking a command is consumed, see `Propagation::Stop`.
+pub struct CommandRouter {
+    prefixes: Vec<CommandPrefix>,
+    commands: Vec<Command>,
+    reply_unknown: bool,
+    priority: u8,
+}
+
+impl From<CommandRouter> for Handler {
+    fn from(router: CommandRouter) -> Self {
+        Handler::event(router)
+    }
+}
+
+impl Default for CommandRouter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CommandRouter {
+    /// Create a CommandRouter with the prefix `/`
+    pub fn new() -> Self {
+        CommandRouter {
+            prefixes: vec![CommandPrefix::text("/")],
+            commands: Vec::new(),
+            reply_unknown: false,
+            priority: DEFAULT_PRIORITY,
+        }
+    }
+
+    /// Replace the prefixes, they are tried in order
+    pub fn prefixes(mut self, prefixes: Vec<CommandPrefix>) -> Self {
+        self.prefixes = prefixes;
+        self
+    }
+
+    pub fn command(mut self, command: Command) -> Self {
+        self.commands.push(command);
+        self
+    }
+
+    /// Set whether "unknown command" is replied to a prefixed message matching no command, it's false by default
+    /// so several bots or routers can share a prefix. The replied message is consumed.
+    pub fn reply_unknown(mut self, reply_unknown: bool) -> Self {
+        self.reply_unknown = reply_unknown;
+        self
+    }
+
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    /// Strip the prefix from the message, returns the prefix shown in the usage and the rest of the message.
+    async fn strip_prefix(
+        &self,
+        matcher: &Matcher,
+        segments: &[MessageSegment],
+    ) -> Option<(String, Vec<MessageSegment>)> {
+        // replying to a message usually adds a Reply segment before the content
+        let start = segments
+            .iter()
+            .position(|segment| !matches!(segment, MessageSegment::Reply { .. }))?;
+        let mut segments = segments[start..].to_vec();
+        for prefix in &self.prefixes {
+            match (prefix, &segments[0]) {
+                (CommandPrefix::Text(prefix), MessageSegment::Text { content }) => {
+                    if let Some(rest) = content.trim_start().strip_prefix(prefix.as_str()) {
+                        segments[0] = MessageSegment::text(rest);
+                        return Some((prefix.clone(), segments));
+                    }
+                }
+                (CommandPrefix::Mention, MessageSegment::At { user_id })
+                    if matcher.bot_id().await.as_ref() == Some(user_id) =>
+                {
+                    segments.remove(0);
+                    return Some((String::new(), segments));
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+#[async_trait]
+impl EventHandlerTrait for CommandRouter {
+    async fn handle_propagation(&self, matcher: Matcher) -> Result<Propagation> {
+        let Some(message) = matcher.try_get_message() else {
+            return Ok(Propagation::Continue);
+        };
+        let Some((prefix, segments)) = self.strip_prefix(&matcher, &message.segments).await else {
+            return Ok(Propagation::Continue);
+        };
+        let mut args = split_args(&segments).into_iter();
+        let Some(CommandArg::Text(name)) = args.next() else {
+            return Ok(Propagation::Continue);
+        };
+
+        let Some(mut command) = self
+            .commands
+            .iter()
+            .find(|command| command.is_called(&name))
+        else {
+            if self.reply_unknown {
+                let reply = format!("Unknown command: {}{}", prefix, name);
+                matcher
+                    .try_send_message(vec![MessageSegment::text(reply)])
+                    .await?;
+                return Ok(Propagation::Stop);
+            }
+            return Ok(Propagation::Continue);
+        };
+        let mut path = vec![command.name.clone()];
+        let mut permitted = command.permission.allows(&matcher);
+        let mut args = args.collect::<Vec<_>>();
+        while let Some(subcommand) = args.first().and_then(|arg| {
+            let name = arg.as_text()?;
+            command
+                .subcommands
+                .iter()
+                .find(|subcommand| subcommand.is_called(name))
+        }) {
+            command = subcommand;
+            path.push(command.name.clone());
+            permitted &= command.permission.allows(&matcher);
+            args.remove(0);
+        }
+
+        let invocation = format!("{}{}", prefix, path.join(" "));
+        match &command.handler {
+            _ if !permitted => {
+                let reply = format!("You are not permitted to run {}", invocation);
+                matcher
+                    .try_send_message(vec![MessageSegment::text(reply)])
+                    .await?;
+            }
+            Some(handler) if command.accepts(args.len()) => {
+                handler
+                    .run(CommandContext {
+                        matcher,
+                        prefix,
+                        command: path,
+                        args,
+                    })
+                    .await?;
+            }
+            _ => {
+                matcher
+                    .try_send_message(vec![MessageSegment::text(command.usage_text(&invocation))])
+                    .await?;
+            }
+        }
+        Ok(Propagation::Stop)
+    }
+
+    fn get_priority(&self) -> u8 {
+        self.priority
+    }
+
+    fn metadata(&self) -> Vec<HandlerMetadata> {
+        // the commands are listed with the first text prefix
+        let prefix = self
+            .prefixes
+            .iter()
+            .find_map(|prefix| match prefix {
+                CommandPrefix::Text(prefix) => Some(prefix.as_str()),
+                CommandPrefix::Mention => None,
+            })
+            .unwrap_or_default();
+        let mut metadata = Vec::new();
+        for command in &self.commands {
+            command.collect_metadata(prefix, &Permission::Everyone, &mut metadata);
+        }
+        metadata
+    }
+}
+
+/// Split the segments into arguments: the text is split by whitespace unless quoted with `"` or `'`,
+/// `\` escapes the next character, and the other segments are kept as they are.
+pub fn split_args(segments: &[MessageSegment]) -> Vec<CommandArg> {
+    let mut args = Vec::new();
+    for segment in segments {
+        let MessageSegment::Text { content } = segment else {
+            args.push(CommandArg::Segment(segment.clone()));
+            continue;
+        };
+
+        let mut current: Option<String> = None;
+        let mut quote = None;
+        let mut chars = content.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    if let Some(escaped) = chars.next() {
+                        current.get_or_insert_with(String::new).push(escaped);
+                    }
+                }
+                '"' | '\'' if quote == Some(c) => quote = None,
+                '"' | '\'' if quote.is_none() => {
+                    quote = Some(c);
+                    current.get_or_insert_with(String::new);
+                }
+                c if c.is_whitespace() && quote.is_none() => {
+                    if let Some(arg) = current.take() {
+                        args.push(CommandArg::Text(arg));
+                    }
+                }
+                c => current.get_or_insert_with(String::new).push(c),
+            }
+        }
+        // an unclosed quote ends with the text segment
+        if let Some(arg) = current {
+            args.push(CommandArg::Text(arg));
+        }
+    }
+    args
+}
+
+/// CommandArgsError is returned when the arguments don't match a CommandArgs struct, it's displayed with the usage.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommandArgsError {
+    pub message: String,
+    pub usage: String,
+}
+
+impl std::fmt::Display for CommandArgsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}\nUsage: {}", self.message, self.usage)
+    }
+}
+
+impl std::error::Error for CommandArgsError {}
+
+/// CommandArgs is a struct parsed from the arguments of a command, usually implemented with `#[derive(CommandArgs)]`
+/// (the `derive` feature):
+/// - `bool` fields are flags, e.g. `--verbose`
+/// - fields with `#[arg(long)]` or `#[arg(short = 'c')]` are options taking a value, e.g. `--count 3`
+/// - the other fields are positional in order, `Option<T>` ones are optional and a `Vec<T>` one takes the rest
+/// - `#[arg(default = "3")]` gives the value of a missing optional or positional argument
+pub trait CommandArgs: Sized {
+    fn parse_args(args: &[CommandArg]) -> Result<Self, CommandArgsError>;
+    /// The usage of the arguments, e.g. `<city> [days] [--verbose]`
+    fn usage() -> String;
+    /// Parse the arguments of a message, its first word being the command
+    fn from_message(message: &Message) -> Result<Self, CommandArgsError> {
+        let args = split_args(&message.segments);
+        let args = match args.first() {
+            Some(CommandArg::Text(_)) => &args[1..],
+            _ => &args[..],
+        };
+        Self::parse_args(args)
+    }
+}
+
+/// FromCommandArg parses a single argument, it's implemented for all the FromStr types and some segments
+pub trait FromCommandArg: Sized {
+    fn from_command_arg(arg: &CommandArg) -> Result<Self, String>;
+}
+
+impl<T: FromStr> FromCommandArg for T {
+    fn from_command_arg(arg: &CommandArg) -> Result<Self, String> {
+        let text = arg.as_text().ok_or("expected a text")?;
+        text.parse()
+            .map_err(|_| format!("invalid value {:?}", text))
+    }
+}
+
+impl FromCommandArg for MessageSegment {
+    fn from_command_arg(arg: &CommandArg) -> Result<Self, String> {
+        match arg {
+            CommandArg::Text(text) => Ok(MessageSegment::text(text.as_str())),
+            CommandArg::Segment(segment) => Ok(segment.clone()),
+        }
+    }
+}
+
+/// At is an argument mentioning a user, with the id of the user
+#[derive(Clone, Debug, PartialEq)]
+pub struct At(pub String);
+
+impl FromCommandArg for At {
+    fn from_command_arg(arg: &CommandArg) -> Result<Self, String> {
+        match arg.as_segment() {
+            Some(MessageSegment::At { user_id }) => Ok(At(user_id.clone())),
+            _ => Err("expected a mention".to_string()),
+        }
+    }
+}
+
+/// Image is an image argument
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image(pub File);
+
+impl FromCommandArg for Image {
+    fn from_command_arg(arg: &CommandArg) -> Result<Self, String> {
+        match arg.as_segment() {
+            Some(MessageSegment::Image { file: Some(file) }) => Ok(Image(file.clone())),
+            _ => Err("expected an image".to_string()),
+        }
+    }
+}
+
+/// ArgParser is used by the code generated by `#[derive(CommandArgs)]`
+#[doc(hidden)]
+pub struct ArgParser<'a> {
+    positionals: VecDeque<&'a CommandArg>,
+    named: Vec<(&'a str, Option<CommandArg>)>,
+    usage: String,
+}
+
+impl<'a> ArgParser<'a> {
+    /// Separate the flags and the options from the positional arguments
+    pub fn new(
+        args: &'a [CommandArg],
+        flags: &[&str],
+        options: &[&str],
+        usage: String,
+    ) -> Result<Self, CommandArgsError> {
+        let mut parser = ArgParser {
+            positionals: VecDeque::new(),
+            named: Vec::new(),
+            usage,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(text) = arg.as_text().filter(|text| text.starts_with('-')) else {
+                parser.positionals.push_back(arg);
+                continue;
+            };
+            let (name, value) = match text.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (text, None),
+            };
+            if flags.contains(&name) && value.is_none() {
+                parser.named.push((name, None));
+            } else if options.contains(&name) {
+                let value = match value {
+                    Some(value) => CommandArg::Text(value.to_string()),
+                    None => match args.next() {
+                        Some(value) => value.clone(),
+                        None => return Err(parser.error(format!("{} requires a value", name))),
+                    },
+                };
+                parser.named.push((name, Some(value)));
+            } else if text.starts_with("--") {
+                return Err(parser.error(format!("unknown option {}", name)));
+            } else {
+                // e.g. a negative number
+                parser.positionals.push_back(arg);
+            }
+        }
+        Ok(parser)
+    }
+
+    pub fn error(&self, message: String) -> CommandArgsError {
+        CommandArgsError {
+            message,
+            usage: self.usage.clone(),
+        }
+    }
+
+    fn parse<T: FromCommandArg>(
+        &self,
+        name: &str,
+        arg: &CommandArg,
+    ) -> Result<T, CommandArgsError> {
+        T::from_command_arg(arg).map_err(|e| self.error(format!("{}: {}", name, e)))
+    }
+
+    pub fn positional<T: FromCommandArg>(&mut self, name: &str) -> Result<T, CommandArgsError> {
+        match self.optional(name)? {
+            Some(value) => Ok(value),
+            None => Err(self.error(format!("missing argument <{}>", name))),
+        }
+    }
+
+    pub fn optional<T: FromCommandArg>(
+        &mut self,
+        name: &str,
+    ) -> Result<Option<T>, CommandArgsError> {
+        match self.positionals.pop_front() {
+            Some(arg) => self.parse(name, arg).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn rest<T: FromCommandArg>(&mut self, name: &str) -> Result<Vec<T>, CommandArgsError> {
+        std::mem::take(&mut self.positionals)
+            .into_iter()
+            .map(|arg| self.parse(name, arg))
+            .collect()
+    }
+
+    pub fn flag(&self, names: &[&str]) -> bool {
+        self.named.iter().any(|(name, _)| names.contains(name))
+    }
+
+    pub fn option<T: FromCommandArg>(
+        &self,
+        names: &[&str],
+        name: &str,
+    ) -> Result<Option<T>, CommandArgsError> {
+        match self
+            .named
+            .iter()
+            .rev()
+            .find_map(|(option, value)| value.as_ref().filter(|_| names.contains(option)))
+        {
+            Some(value) => self.parse(name, value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn default<T: FromCommandArg>(
+        &self,
+        name: &str,
+        value: &str,
+    ) -> Result<T, CommandArgsError> {
+        self.parse(name, &CommandArg::Text(value.to_string()))
+    }
+
+    pub fn missing(&self, name: &str) -> CommandArgsError {
+        self.error(format!("missing option {}", name))
+    }
+
+    /// Fail if there are arguments left
+    pub fn finish(self) -> Result<(), CommandArgsError> {
+        match self.positionals.front() {
+            Some(CommandArg::Text(text)) => {
+                Err(self.error(format!("unexpected argument {:?}", text)))
+            }
+            Some(CommandArg::Segment(_)) => Err(self.error("unexpected argument".to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::message;
+
+    fn texts(args: &[CommandArg]) -> Vec<&str> {
+        args.iter().filter_map(CommandArg::as_text).collect()
+    }
+
+    fn parser<'a>(args: &'a [CommandArg]) -> Result<ArgParser<'a>, CommandArgsError> {
+        ArgParser::new(
+            args,
+            &["--verbose", "-v"],
+            &["--count", "-c"],
+            "usage".to_string(),
+        )
+    }
+
+    #[test]
+    fn split_args_respects_quotes_and_escapes() {
+        let args = split_args(&[MessageSegment::text(
+            r#"say  "hello world" 'it''s' a\ b \"x"#,
+        )]);
+        assert_eq!(texts(&args), ["say", "hello world", "its", "a b", "\"x"]);
+        // an empty quote is still an argument, an unclosed one runs to the end of the text
+        let args = split_args(&[MessageSegment::text(r#"a "" "b c"#)]);
+        assert_eq!(texts(&args), ["a", "", "b c"]);
+    }
+
+    #[test]
+    fn split_args_keeps_segments() {
+        let mention = MessageSegment::At {
+            user_id: "1".to_string(),
+        };
+        let args = split_args(&[
+            MessageSegment::text("ban "),
+            mention.clone(),
+            MessageSegment::text(" 10"),
+        ]);
+        assert_eq!(args.len(), 3);
+        assert_eq!(args[1].as_segment(), Some(&mention));
+        assert_eq!(texts(&args), ["ban", "10"]);
+    }
+
+    #[test]
+    fn arg_parser_separates_flags_options_and_positionals() {
+        let args = split_args(&[MessageSegment::text("a -v --count=3 b -c 4 -5")]);
+        let mut parser = parser(&args).unwrap();
+        assert!(parser.flag(&["--verbose", "-v"]));
+        // the last option wins
+        assert_eq!(
+            parser.option::<u32>(&["--count", "-c"], "count").unwrap(),
+            Some(4)
+        );
+        assert_eq!(parser.positional::<String>("x").unwrap(), "a");
+        assert_eq!(
+            parser.optional::<String>("y").unwrap().as_deref(),
+            Some("b")
+        );
+        // an unknown short name is positional, e.g. a negative number
+        assert_eq!(parser.rest::<i32>("rest").unwrap(), [-5]);
+        assert!(parser.finish().is_ok());
+    }
+
+    #[test]
+    fn arg_parser_errors() {
+        let args = split_args(&[MessageSegment::text("--count")]);
+        assert_eq!(
+            parser(&args).err().unwrap().message,
+            "--count requires a value"
+        );
+        let args = split_args(&[MessageSegment::text("--nope")]);
+        assert_eq!(
+            parser(&args).err().unwrap().message,
+            "unknown option --nope"
+        );
+        let args = split_args(&[MessageSegment::text("--verbose=1")]);
+        assert_eq!(
+            parser(&args).err().unwrap().message,
+            "unknown option --verbose"
+        );
+
+        let args = split_args(&[MessageSegment::text("x y")]);
+        let mut parser = parser(&args).unwrap();
+        let error = parser.positional::<u32>("n").unwrap_err();
+        assert!(error.message.starts_with("n: "));
+        assert_eq!(error.usage, "usage");
+        assert_eq!(
+            parser.finish().unwrap_err().message,
+            "unexpected argument \"y\""
+        );
+    }
+
+    #[test]
+    fn arg_parser_missing_and_default() {
+        let args = Vec::new();
+        let mut parser = parser(&args).unwrap();
+        assert_eq!(parser.optional::<u32>("n").unwrap(), None);
+        assert_eq!(
+            parser.positional::<u32>("n").unwrap_err().message,
+            "missing argument <n>"
+        );
+        assert_eq!(parser.default::<u32>("n", "3").unwrap(), 3);
+        assert!(!parser.flag(&["--verbose"]));
+    }
+
+    #[test]
+    fn subcommands_need_the_permission_of_their_parent() {
+        let superuser = Permission::Users(vec![("test".to_string(), "1".to_string())]);
+        let command = Command::new("admin")
+            .permission(Permission::GroupAdmin)
+            .subcommand(
+                Command::new("ban")
+                    .permission(superuser.clone())
+                    .handler(|_ctx: CommandContext| async { Ok(()) }),
+            );
+        let mut metadata = Vec::new();
+        command.collect_metadata("/", &Permission::Everyone, &mut metadata);
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(metadata[0].name, "/admin ban");
+        assert_eq!(
+            metadata[0].permission,
+            Permission::All(vec![Permission::GroupAdmin, superuser.clone()])
+        );
+        // the superuser isn't an admin of the group
+        assert!(superuser.allows(&message(Some("g"), "1", "x")));
+        assert!(!metadata[0].permission.allows(&message(Some("g"), "1", "x")));
+    }
+
+    #[test]
+    fn users_are_permitted_on_their_server() {
+        let permission = Permission::Users(vec![("other".to_string(), "1".to_string())]);
+        assert!(!permission.allows(&message(None, "1", "x")));
+        let permission = Permission::Users(vec![("test".to_string(), "1".to_string())]);
+        assert!(permission.allows(&message(None, "1", "x")));
+        assert!(!permission.allows(&message(None, "2", "x")));
+    }
+}
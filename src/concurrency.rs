@@ -0,0 +1,380 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::matcher::Matcher;
+
+/// OverflowPolicy decides what happens to an event when the event handler is already running at its limit
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum OverflowPolicy {
+    /// Wait until a running call of the event handler finished
+    #[default]
+    Queue,
+    /// Don't run the event handler for the event
+    Drop,
+}
+
+/// HandlerLimits bounds how many calls of an event handler run at once and how long each of them may run
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct HandlerLimits {
+    /// The maximum number of calls running at once
+    pub max_concurrency: Option<usize>,
+    /// The maximum number of calls running at once for the same user
+    pub max_concurrency_per_user: Option<usize>,
+    /// The maximum number of calls running at once for the same group
+    pub max_concurrency_per_group: Option<usize>,
+    pub overflow: OverflowPolicy,
+    /// A call running longer than this is cancelled
+    pub timeout: Option<Duration>,
+}
+
+impl HandlerLimits {
+    pub fn max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = Some(max_concurrency);
+        self
+    }
+
+    pub fn max_concurrency_per_user(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency_per_user = Some(max_concurrency);
+        self
+    }
+
+    pub fn max_concurrency_per_group(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency_per_group = Some(max_concurrency);
+        self
+    }
+
+    pub fn overflow(mut self, overflow: OverflowPolicy) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// KeyedSemaphore keeps a Semaphore for each key, the idle ones are removed.
+struct KeyedSemaphore<K> {
+    permits: usize,
+    semaphores: Arc<Mutex<HashMap<K, Arc<Semaphore>>>>,
+}
+
+impl<K: Hash + Eq + Clone> KeyedSemaphore<K> {
+    fn new(permits: usize) -> Self {
+        KeyedSemaphore {
+            permits: permits.max(1),
+            semaphores: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    async fn acquire(&self, key: K, overflow: OverflowPolicy) -> Option<KeyedPermit<K>> {
+        let semaphore = self
+            .semaphores
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.permits)))
+            .clone();
+        let permit = acquire(semaphore, overflow).await;
+        let permit = KeyedPermit {
+            permit,
+            key,
+            semaphores: self.semaphores.clone(),
+        };
+        // the KeyedPermit removes the idle semaphore even if it wasn't acquired
+        permit.permit.is_some().then_some(permit)
+    }
+}
+
+struct KeyedPermit<K: Hash + Eq> {
+    permit: Option<OwnedSemaphorePermit>,
+    key: K,
+    semaphores: Arc<Mutex<HashMap<K, Arc<Semaphore>>>>,
+}
+
+impl<K: Hash + Eq> Drop for KeyedPermit<K> {
+    fn drop(&mut self) {
+        self.permit.take();
+        let mut semaphores = self.semaphores.lock().unwrap_or_else(|e| e.into_inner());
+        // only the map holds the semaphore, nobody is running or waiting
+        if semaphores
+            .get(&self.key)
+            .is_some_and(|semaphore| Arc::strong_count(semaphore) == 1)
+        {
+            semaphores.remove(&self.key);
+        }
+    }
+}
+
+async fn acquire(
+    semaphore: Arc<Semaphore>,
+    overflow: OverflowPolicy,
+) -> Option<OwnedSemaphorePermit> {
+    match overflow {
+        OverflowPolicy::Queue => semaphore.acquire_owned().await.ok(),
+        OverflowPolicy::Drop => semaphore.try_acquire_owned().ok(),
+    }
+}
+
+/// The permits held by a running call of an event handler
+pub(crate) struct ConcurrencyPermit {
+    _global: Option<OwnedSemaphorePermit>,
+    _user: Option<KeyedPermit<(&'static str, String)>>,
+    _group: Option<KeyedPermit<(&'static str, String)>>,
+}
+
+/// ConcurrencyLimiter enforces the HandlerLimits of an event handler
+pub(crate) struct ConcurrencyLimiter {
+    limits: HandlerLimits,
+    global: Option<Arc<Semaphore>>,
+    per_user: Option<KeyedSemaphore<(&'static str, String)>>,
+    per_group: Option<KeyedSemaphore<(&'static str, String)>>,
+}
+
+impl ConcurrencyLimiter {
+    pub(crate) fn new(limits: HandlerLimits) -> Self {
+        ConcurrencyLimiter {
+            global: limits
+                .max_concurrency
+                .map(|permits| Arc::new(Semaphore::new(permits.max(1)))),
+            per_user: limits.max_concurrency_per_user.map(KeyedSemaphore::new),
+            per_group: limits.max_concurrency_per_group.map(KeyedSemaphore::new),
+            limits,
+        }
+    }
+
+    pub(crate) fn timeout(&self) -> Option<Duration> {
+        self.limits.timeout
+    }
+
+    /// Acquire the permits to run the event handler with the matcher,
+    /// None if the OverflowPolicy is `Drop` and the event handler is running at its limit.
+    pub(crate) async fn acquire(&self, matcher: &Matcher) -> Option<ConcurrencyPermit> {
+        let overflow = self.limits.overflow;
+        let server = matcher.bot.server();
+
+        let group = match (&self.per_group, matcher.try_get_group()) {
+            (Some(per_group), Some(group)) => Some(
+                per_group
+                    .acquire((server, group.id.clone()), overflow)
+                    .await?,
+            ),
+            _ => None,
+        };
+        let user = match (&self.per_user, matcher.try_get_user()) {
+            (Some(per_user), Some(user)) => Some(
+                per_user
+                    .acquire((server, user.id.clone()), overflow)
+                    .await?,
+            ),
+            _ => None,
+        };
+        let global = match &self.global {
+            Some(global) => Some(acquire(global.clone(), overflow).await?),
+            None => None,
+        };
+
+        Some(ConcurrencyPermit {
+            _global: global,
+            _user: user,
+            _group: group,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        handler::{EventHandlerPool, EventHandlerTrait, Handler, Propagation},
+        testing::message,
+    };
+
+    fn limiter(limits: HandlerLimits) -> Arc<ConcurrencyLimiter> {
+        Arc::new(ConcurrencyLimiter::new(limits))
+    }
+
+    #[tokio::test]
+    async fn drop_policy_skips_the_busy_handler() {
+        let limiter = limiter(
+            HandlerLimits::default()
+                .max_concurrency(1)
+                .overflow(OverflowPolicy::Drop),
+        );
+        let matcher = message(Some("g"), "1", "hi");
+        let running = limiter.acquire(&matcher).await;
+        assert!(running.is_some());
+        assert!(limiter.acquire(&matcher).await.is_none());
+        drop(running);
+        assert!(limiter.acquire(&matcher).await.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queue_policy_waits_for_the_running_call() {
+        let limiter = limiter(HandlerLimits::default().max_concurrency(1));
+        let running = limiter.acquire(&message(Some("g"), "1", "hi")).await;
+        let queued = tokio::spawn({
+            let limiter = limiter.clone();
+            async move {
+                limiter
+                    .acquire(&message(Some("h"), "2", "hi"))
+                    .await
+                    .is_some()
+            }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!queued.is_finished());
+        drop(running);
+        assert!(queued.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn users_and_groups_are_limited_separately() {
+        let per_user = limiter(
+            HandlerLimits::default()
+                .max_concurrency_per_user(1)
+                .overflow(OverflowPolicy::Drop),
+        );
+        let _running = per_user.acquire(&message(Some("g"), "1", "hi")).await;
+        assert!(per_user
+            .acquire(&message(Some("h"), "1", "hi"))
+            .await
+            .is_none());
+        assert!(per_user
+            .acquire(&message(Some("g"), "2", "hi"))
+            .await
+            .is_some());
+
+        let per_group = limiter(
+            HandlerLimits::default()
+                .max_concurrency_per_group(1)
+                .overflow(OverflowPolicy::Drop),
+        );
+        let _running = per_group.acquire(&message(Some("g"), "1", "hi")).await;
+        assert!(per_group
+            .acquire(&message(Some("g"), "2", "hi"))
+            .await
+            .is_none());
+        assert!(per_group
+            .acquire(&message(Some("h"), "1", "hi"))
+            .await
+            .is_some());
+        // the private messages have no group to limit
+        assert!(per_group.acquire(&message(None, "1", "hi")).await.is_some());
+    }
+
+    fn available(semaphores: &KeyedSemaphore<(&'static str, String)>, id: &str) -> Option<usize> {
+        semaphores
+            .semaphores
+            .lock()
+            .unwrap()
+            .get(&("test", id.to_string()))
+            .map(|semaphore| semaphore.available_permits())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn permits_are_acquired_group_then_user_then_global() {
+        let limiter = limiter(
+            HandlerLimits::default()
+                .max_concurrency(1)
+                .max_concurrency_per_user(1)
+                .max_concurrency_per_group(1),
+        );
+        let running = limiter.acquire(&message(Some("h"), "2", "hi")).await;
+        let queued = tokio::spawn({
+            let limiter = limiter.clone();
+            async move {
+                limiter
+                    .acquire(&message(Some("g"), "1", "hi"))
+                    .await
+                    .is_some()
+            }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // the queued call holds its group and user permits while waiting for the global one
+        assert_eq!(available(limiter.per_group.as_ref().unwrap(), "g"), Some(0));
+        assert_eq!(available(limiter.per_user.as_ref().unwrap(), "1"), Some(0));
+        drop(running);
+        assert!(queued.await.unwrap());
+        // the idle semaphores are removed
+        assert_eq!(available(limiter.per_group.as_ref().unwrap(), "g"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn overlapping_calls_never_deadlock() {
+        let limiter = limiter(
+            HandlerLimits::default()
+                .max_concurrency(2)
+                .max_concurrency_per_user(1)
+                .max_concurrency_per_group(1),
+        );
+        let mut calls = tokio::task::JoinSet::new();
+        for call in 0..40 {
+            let limiter = limiter.clone();
+            let group = ["g", "h", "i"][call % 3].to_string();
+            let user = ["1", "2"][call % 2].to_string();
+            calls.spawn(async move {
+                let permit = limiter.acquire(&message(Some(&group), &user, "hi")).await;
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                permit.is_some()
+            });
+        }
+        let finished = tokio::time::timeout(Duration::from_secs(60), async {
+            let mut acquired = 0;
+            while let Some(call) = calls.join_next().await {
+                acquired += call.unwrap() as usize;
+            }
+            acquired
+        })
+        .await;
+        assert_eq!(finished.unwrap(), 40);
+    }
+
+    /// An event handler running until it's cancelled
+    struct Endless {
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl EventHandlerTrait for Endless {
+        async fn handle_propagation(&self, _matcher: Matcher) -> Result<Propagation> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            std::future::pending().await
+        }
+
+        fn limits(&self) -> HandlerLimits {
+            HandlerLimits::default()
+                .max_concurrency(1)
+                .overflow(OverflowPolicy::Drop)
+                .timeout(Duration::from_secs(5))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timed_out_calls_are_cancelled_and_release_their_permits() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut pool = EventHandlerPool::new();
+        pool.add_handler(Handler::event(Endless {
+            calls: calls.clone(),
+        }));
+        for _ in 0..2 {
+            let handling = pool.handle(message(Some("g"), "1", "hi"), None);
+            tokio::time::timeout(Duration::from_secs(6), handling)
+                .await
+                .unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
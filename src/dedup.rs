@@ -0,0 +1,381 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    bot::{current_registry, BotId, BotObject, BotState},
+    filter::{MiddlewareOutcome, MiddlewareTrait},
+    matcher::Matcher,
+};
+
+const PRUNE_INTERVAL: u64 = 1024;
+
+/// DedupStrategy decides how the copies of the same message received by different bots are recognised
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum DedupStrategy {
+    /// The same message id in the same group, for the servers where the bots share the message ids.
+    /// The messages without an id are recognised by their content, like with `Content`.
+    #[default]
+    MessageId,
+    /// The same sender and content in the same group within the window, received by another bot,
+    /// for the servers where each bot sees its own message ids.
+    /// The same message sent twice to a bot isn't a duplicate.
+    Content,
+}
+
+/// The last copy of a message seen in a group
+struct Seen {
+    at: Instant,
+    /// The id of the bot that received it
+    bot_id: String,
+}
+
+/// The bot responsible for a group
+struct Elected {
+    bot: BotObject,
+    bot_id: String,
+    last_seen: Instant,
+}
+
+/// Dedup is a built-in middleware for bot accounts sitting in the same groups:
+/// a group message received by several bots of the same server is only handled once.
+///
+/// Each group has a responsible bot, the first one seen there, so the replies come from the same account.
+/// With `DedupStrategy::MessageId`, the first copy of a message is handled, through the responsible bot.
+/// With `DedupStrategy::Content`, only the copies received by the responsible bot are handled.
+/// Another bot takes over when the responsible one received nothing from the group within the failover time,
+/// or isn't connected anymore.
+pub struct Dedup {
+    strategy: DedupStrategy,
+    window: Duration,
+    failover: Duration,
+    priority: u8,
+    seen: Mutex<HashMap<(&'static str, u64), Seen>>,
+    elected: Mutex<HashMap<(&'static str, String), Elected>>,
+    checked: AtomicU64,
+}
+
+impl Default for Dedup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dedup {
+    pub fn new() -> Self {
+        Dedup {
+            strategy: DedupStrategy::default(),
+            window: Duration::from_secs(10),
+            failover: Duration::from_secs(60),
+            priority: 4,
+            seen: Mutex::new(HashMap::new()),
+            elected: Mutex::new(HashMap::new()),
+            checked: AtomicU64::new(0),
+        }
+    }
+
+    pub fn strategy(mut self, strategy: DedupStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// The copies of a message arriving within this time are duplicates, 10 seconds by default
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// A responsible bot that received nothing from its group for this long is replaced, 60 seconds by default
+    pub fn failover(mut self, failover: Duration) -> Self {
+        self.failover = failover;
+        self
+    }
+
+    /// Set the priority of the middleware, 4 by default so the duplicates are dropped before the other filters see them
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Identify the message in its group, None for the events that are not group messages.
+    /// The boolean is true when the message is recognised by its content rather than by its id.
+    fn fingerprint(&self, matcher: &Matcher) -> Option<(String, u64, bool)> {
+        let group = matcher.try_get_group()?;
+        let message = matcher.try_get_message()?;
+        let mut hasher = DefaultHasher::new();
+        group.id.hash(&mut hasher);
+        let by_content = match self.strategy {
+            DedupStrategy::MessageId if !message.id.is_empty() => {
+                message.id.hash(&mut hasher);
+                false
+            }
+            _ => {
+                matcher
+                    .try_get_user()
+                    .map(|user| &user.id)
+                    .hash(&mut hasher);
+                message.get_raw_text().hash(&mut hasher);
+                true
+            }
+        };
+        Some((group.id.clone(), hasher.finish(), by_content))
+    }
+
+    /// Whether a copy of the message was already seen within the window, the message is marked as seen.
+    /// A message recognised by its content is only a copy when another bot received it,
+    /// a bot receiving the same content again got it sent twice.
+    fn is_duplicate(&self, key: (&'static str, u64), bot_id: &str, by_content: bool) -> bool {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        if self.checked.fetch_add(1, Ordering::Relaxed) % PRUNE_INTERVAL == 0 {
+            seen.retain(|_, seen| now.duration_since(seen.at) < self.window);
+            self.elected
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .retain(|_, elected| now.duration_since(elected.last_seen) < self.failover);
+        }
+        match seen.get(&key) {
+            Some(seen)
+                if now.duration_since(seen.at) < self.window
+                    && !(by_content && seen.bot_id == bot_id) =>
+            {
+                true
+            }
+            _ => {
+                seen.insert(
+                    key,
+                    Seen {
+                        at: now,
+                        bot_id: bot_id.to_string(),
+                    },
+                );
+                false
+            }
+        }
+    }
+
+    /// Get the responsible bot of the group, electing the bot of the event if there is none or it failed.
+    /// None if the bot of the event is the responsible one.
+    async fn elect(
+        &self,
+        matcher: &Matcher,
+        bot_id: String,
+        group: String,
+    ) -> Option<(BotObject, String)> {
+        let server = matcher.bot.server();
+        let elect = |elected: &mut HashMap<(&'static str, String), Elected>| {
+            elected.insert(
+                (server, group.clone()),
+                Elected {
+                    bot: matcher.bot.clone(),
+                    bot_id: bot_id.clone(),
+                    last_seen: Instant::now(),
+                },
+            );
+            tracing::info!(
+                "Bot {} on {} is now responsible for group {}",
+                bot_id,
+                server,
+                group
+            );
+            None
+        };
+
+        let responsible = {
+            let mut elected = self.elected.lock().unwrap_or_else(|e| e.into_inner());
+            match elected.get_mut(&(server, group.clone())) {
+                Some(current) if current.bot_id == bot_id => {
+                    current.last_seen = Instant::now();
+                    return None;
+                }
+                Some(current) if current.last_seen.elapsed() < self.failover => {
+                    (current.bot.clone(), current.bot_id.clone())
+                }
+                _ => return elect(&mut elected),
+            }
+        };
+
+        // the responsible bot may have been removed or disconnected before the failover time
+        let connected = match current_registry() {
+            Some(registry) => {
+                registry.bot_state(server, &responsible.1).await == Some(BotState::Connected)
+            }
+            None => true,
+        };
+        if connected {
+            return Some(responsible);
+        }
+        let mut elected = self.elected.lock().unwrap_or_else(|e| e.into_inner());
+        // another event may have elected a bot meanwhile
+        match elected.get(&(server, group.clone())) {
+            Some(current) if current.bot_id != responsible.1 => {
+                Some((current.bot.clone(), current.bot_id.clone()))
+            }
+            _ => elect(&mut elected),
+        }
+    }
+}
+
+#[async_trait]
+impl MiddlewareTrait for Dedup {
+    async fn process(&self, mut matcher: Matcher) -> MiddlewareOutcome {
+        let Some((group, fingerprint, by_content)) = self.fingerprint(&matcher) else {
+            return MiddlewareOutcome::Next(matcher);
+        };
+        let Some(bot_id) = matcher.bot_id().await else {
+            return MiddlewareOutcome::Next(matcher);
+        };
+        let server = matcher.bot.server();
+        let responsible = self.elect(&matcher, bot_id.clone(), group).await;
+        if responsible.is_some() && self.strategy == DedupStrategy::Content {
+            // the message ids differ between the bots, so the copy can't be handled through the responsible bot
+            return MiddlewareOutcome::Reject;
+        }
+        if self.is_duplicate((server, fingerprint), &bot_id, by_content) {
+            tracing::debug!("Dropped a duplicate message on {}", server);
+            return MiddlewareOutcome::Reject;
+        }
+        if let Some((responsible, responsible_id)) = responsible {
+            matcher.bot = responsible;
+            matcher.bot_id = Some(BotId::known(responsible_id));
+        }
+        MiddlewareOutcome::Next(matcher)
+    }
+
+    fn get_priority(&self) -> u8 {
+        self.priority
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{message_to, TestBot};
+
+    /// The id of the TestBot the matcher passed by the middleware is sent through
+    async fn handled_by(dedup: &Dedup, matcher: Matcher) -> Option<String> {
+        match dedup.process(matcher).await {
+            MiddlewareOutcome::Next(matcher) => {
+                let bot = matcher.bot.as_any().downcast_ref::<TestBot>().unwrap();
+                bot.id.clone()
+            }
+            _ => None,
+        }
+    }
+
+    fn with_id(mut matcher: Matcher, id: &str) -> Matcher {
+        matcher.try_get_message_mut().unwrap().id = id.to_string();
+        matcher
+    }
+
+    #[tokio::test]
+    async fn content_repeats_of_one_bot_are_handled() {
+        let (a, b) = (TestBot::new("a"), TestBot::new("b"));
+        let dedup = Dedup::new().strategy(DedupStrategy::Content);
+        for _ in 0..2 {
+            let handled = handled_by(&dedup, message_to(&a, Some("g"), "1", "hi")).await;
+            assert_eq!(handled.as_deref(), Some("a"));
+            assert_eq!(
+                handled_by(&dedup, message_to(&b, Some("g"), "1", "hi")).await,
+                None
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn content_copies_are_dropped_after_a_failover() {
+        let (a, b) = (TestBot::new("a"), TestBot::new("b"));
+        let dedup = Dedup::new()
+            .strategy(DedupStrategy::Content)
+            .failover(Duration::ZERO);
+        let handled = handled_by(&dedup, message_to(&a, Some("g"), "1", "hi")).await;
+        assert_eq!(handled.as_deref(), Some("a"));
+        // b takes over at once, but the copy of the message a received is still a duplicate
+        assert_eq!(
+            handled_by(&dedup, message_to(&b, Some("g"), "1", "hi")).await,
+            None
+        );
+        let handled = handled_by(&dedup, message_to(&b, Some("g"), "1", "bye")).await;
+        assert_eq!(handled.as_deref(), Some("b"));
+    }
+
+    #[tokio::test]
+    async fn message_ids_are_handled_once_through_the_responsible_bot() {
+        let (a, b) = (TestBot::new("a"), TestBot::new("b"));
+        let dedup = Dedup::new();
+        let handled = handled_by(&dedup, with_id(message_to(&a, Some("g"), "1", "hi"), "1")).await;
+        assert_eq!(handled.as_deref(), Some("a"));
+        let copy = with_id(message_to(&b, Some("g"), "1", "hi"), "1");
+        assert_eq!(handled_by(&dedup, copy).await, None);
+        // b received the message first, it's still answered by a
+        let handled = handled_by(&dedup, with_id(message_to(&b, Some("g"), "1", "hi"), "2")).await;
+        assert_eq!(handled.as_deref(), Some("a"));
+        let copy = with_id(message_to(&a, Some("g"), "1", "hi"), "2");
+        assert_eq!(handled_by(&dedup, copy).await, None);
+    }
+
+    #[tokio::test]
+    async fn messages_without_id_are_recognised_by_content() {
+        let (a, b) = (TestBot::new("a"), TestBot::new("b"));
+        let dedup = Dedup::new();
+        for _ in 0..2 {
+            let handled = handled_by(&dedup, message_to(&a, Some("g"), "1", "hi")).await;
+            assert_eq!(handled.as_deref(), Some("a"));
+            assert_eq!(
+                handled_by(&dedup, message_to(&b, Some("g"), "1", "hi")).await,
+                None
+            );
+        }
+        // another sender with the same content isn't a copy
+        let handled = handled_by(&dedup, message_to(&b, Some("g"), "2", "hi")).await;
+        assert_eq!(handled.as_deref(), Some("a"));
+    }
+
+    #[tokio::test]
+    async fn copies_after_the_window_are_handled() {
+        let (a, b) = (TestBot::new("a"), TestBot::new("b"));
+        let dedup = Dedup::new().window(Duration::ZERO);
+        let handled = handled_by(&dedup, with_id(message_to(&a, Some("g"), "1", "hi"), "1")).await;
+        assert_eq!(handled.as_deref(), Some("a"));
+        let copy = with_id(message_to(&b, Some("g"), "1", "hi"), "1");
+        assert_eq!(handled_by(&dedup, copy).await.as_deref(), Some("a"));
+    }
+
+    #[tokio::test]
+    async fn private_messages_are_not_deduplicated() {
+        let (a, b) = (TestBot::new("a"), TestBot::new("b"));
+        let dedup = Dedup::new().strategy(DedupStrategy::Content);
+        let handled = handled_by(&dedup, message_to(&a, None, "1", "hi")).await;
+        assert_eq!(handled.as_deref(), Some("a"));
+        let handled = handled_by(&dedup, message_to(&b, None, "1", "hi")).await;
+        assert_eq!(handled.as_deref(), Some("b"));
+    }
+
+    #[tokio::test]
+    async fn the_cached_bot_id_is_used() {
+        let dedup = Dedup::new();
+        let mut matcher = message_to(&TestBot::default(), Some("g"), "1", "hi");
+        assert!(matches!(
+            dedup.process(matcher.clone()).await,
+            MiddlewareOutcome::Next(_)
+        ));
+        matcher.bot_id = Some(BotId::known("a".to_string()));
+        assert!(matches!(
+            dedup.process(matcher).await,
+            MiddlewareOutcome::Next(_)
+        ));
+        assert!(dedup
+            .elected
+            .lock()
+            .unwrap()
+            .contains_key(&("test", "g".to_string())));
+    }
+}
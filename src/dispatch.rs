@@ -0,0 +1,364 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    bot::BotRegistry, event::Event, filter::FilterPool, handler::EventHandlerPool, matcher::Matcher,
+};
+
+/// OrderingMode decides in which order the events of the same conversation (a group or a private chat) are handled.
+/// Events of different conversations are always handled concurrently.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum OrderingMode {
+    /// Events are handled concurrently in no particular order
+    Unordered,
+    /// The filters see the events of a conversation one by one in arrival order,
+    /// and every handler is started with them in arrival order,
+    /// but a handler may still be handling several events of the same conversation at once.
+    #[default]
+    Ordered,
+    /// The events of a conversation are handled one by one:
+    /// an event only reaches the filters after all handlers finished the previous one.
+    Serialized,
+}
+
+/// The chat that an event belongs to
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Chat {
+    Group(String),
+    Private(String),
+}
+
+/// ConversationKey identifies the conversation that an event belongs to
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ConversationKey {
+    pub server: &'static str,
+    pub chat: Chat,
+}
+
+impl ConversationKey {
+    /// Get the conversation of the event, None if it doesn't belong to a group or user, e.g. a MetaEvent
+    pub fn from_matcher(matcher: &Matcher) -> Option<Self> {
+        let chat = match (matcher.try_get_group(), matcher.try_get_user()) {
+            (Some(group), _) => Chat::Group(group.id.clone()),
+            (None, Some(user)) => Chat::Private(user.id.clone()),
+            (None, None) => return None,
+        };
+        Some(ConversationKey {
+            server: matcher.bot.server(),
+            chat,
+        })
+    }
+}
+
+/// Turn lets a step of an event start only after the same step of the previous event in the conversation has started.
+/// The step has started when the Turn is dropped.
+pub(crate) struct Turn {
+    previous: Option<Arc<Semaphore>>,
+    done: Arc<Semaphore>,
+}
+
+impl Turn {
+    fn chain(last: &mut Arc<Semaphore>) -> Self {
+        let done = Arc::new(Semaphore::new(0));
+        let previous = std::mem::replace(last, done.clone());
+        Turn {
+            previous: Some(previous),
+            done,
+        }
+    }
+
+    /// Wait until the previous event passed this step
+    pub(crate) async fn wait(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            // the semaphore is closed when the previous Turn is dropped
+            let _ = previous.acquire().await;
+        }
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        self.done.close();
+    }
+}
+
+fn passed_turn() -> Arc<Semaphore> {
+    let semaphore = Arc::new(Semaphore::new(0));
+    semaphore.close();
+    semaphore
+}
+
+/// The last Turns taken in a conversation
+struct ConversationTurns {
+    pipeline: Arc<Semaphore>,
+    handlers: Vec<Arc<Semaphore>>,
+    /// The permits of the events of the conversation being dispatched, only in lossless mode
+    backlog: Option<Arc<Semaphore>>,
+}
+
+impl ConversationTurns {
+    /// Whether no event of the conversation is being dispatched, given the size of the backlog
+    fn is_idle(&self, backlog: usize) -> bool {
+        self.pipeline.is_closed()
+            && self.handlers.iter().all(|turn| turn.is_closed())
+            && self
+                .backlog
+                .as_ref()
+                .map_or(true, |permits| permits.available_permits() == backlog)
+    }
+}
+
+/// The Turns an event has to take before passing the filters and reaching each handler
+#[derive(Default)]
+pub(crate) struct EventTurns {
+    pipeline: Option<Turn>,
+    handlers: Option<Vec<Turn>>,
+    /// Held until the event was dispatched
+    _backlog: Option<OwnedSemaphorePermit>,
+}
+
+/// InFlight bounds the events being dispatched in lossless mode, so the bots are backpressured instead of piling up dispatch tasks
+pub(crate) struct InFlight {
+    /// The events past their pipeline Turn, so the events waiting for their Turn don't hold up the other conversations
+    running: Arc<Semaphore>,
+    /// The events of a conversation being dispatched, the event loop only waits when a single conversation is that far behind
+    backlog: usize,
+}
+
+impl InFlight {
+    pub(crate) fn new(capacity: usize) -> Self {
+        InFlight {
+            running: Arc::new(Semaphore::new(capacity.max(1))),
+            backlog: capacity.max(1),
+        }
+    }
+}
+
+const PRUNE_INTERVAL: u64 = 1024;
+
+/// Dispatcher runs the filters and handlers for each event, it's shared by the tasks dispatching the events.
+pub(crate) struct Dispatcher {
+    pub(crate) handler_pool: EventHandlerPool,
+    filter_pool: FilterPool,
+    bot_registry: BotRegistry,
+    ordering: OrderingMode,
+    ignore_own_messages: bool,
+    in_flight: Option<InFlight>,
+    conversations: Mutex<HashMap<ConversationKey, ConversationTurns>>,
+    prepared: AtomicU64,
+}
+
+impl Dispatcher {
+    pub(crate) fn new(
+        handler_pool: EventHandlerPool,
+        filter_pool: FilterPool,
+        bot_registry: BotRegistry,
+        ordering: OrderingMode,
+        ignore_own_messages: bool,
+        in_flight: Option<InFlight>,
+    ) -> Self {
+        Dispatcher {
+            handler_pool,
+            filter_pool,
+            bot_registry,
+            ordering,
+            ignore_own_messages,
+            in_flight,
+            conversations: Mutex::new(HashMap::new()),
+            prepared: AtomicU64::new(0),
+        }
+    }
+
+    /// Run the function with the Turns of the conversation
+    fn with_conversation<T>(
+        &self,
+        key: ConversationKey,
+        f: impl FnOnce(&mut ConversationTurns) -> T,
+    ) -> T {
+        let mut conversations = self.conversations.lock().unwrap_or_else(|e| e.into_inner());
+        if self.prepared.fetch_add(1, Ordering::Relaxed) % PRUNE_INTERVAL == 0 {
+            let backlog = self
+                .in_flight
+                .as_ref()
+                .map_or(0, |in_flight| in_flight.backlog);
+            conversations.retain(|_, turns| !turns.is_idle(backlog));
+        }
+
+        let handler_count = self.handler_pool.event_handler_count();
+        let turns = conversations
+            .entry(key)
+            .or_insert_with(|| ConversationTurns {
+                pipeline: passed_turn(),
+                handlers: (0..handler_count).map(|_| passed_turn()).collect(),
+                backlog: self
+                    .in_flight
+                    .as_ref()
+                    .map(|in_flight| Arc::new(Semaphore::new(in_flight.backlog))),
+            });
+        f(turns)
+    }
+
+    /// Take the Turns of the event in its conversation, must be called in arrival order.
+    /// In lossless mode, this waits while the conversation has too many events being dispatched.
+    pub(crate) async fn prepare(&self, matcher: &Matcher) -> EventTurns {
+        let Some(key) = ConversationKey::from_matcher(matcher) else {
+            return EventTurns::default();
+        };
+        let backlog = match self.with_conversation(key.clone(), |turns| turns.backlog.clone()) {
+            Some(backlog) => backlog.acquire_owned().await.ok(),
+            None => None,
+        };
+        if self.ordering == OrderingMode::Unordered {
+            return EventTurns {
+                _backlog: backlog,
+                ..Default::default()
+            };
+        }
+
+        self.with_conversation(key, |turns| {
+            let pipeline = Some(Turn::chain(&mut turns.pipeline));
+            let handlers = match self.ordering {
+                OrderingMode::Ordered => Some(turns.handlers.iter_mut().map(Turn::chain).collect()),
+                // the pipeline Turn is held until the handlers finished, so they are already in order
+                _ => None,
+            };
+            EventTurns {
+                pipeline,
+                handlers,
+                _backlog: backlog,
+            }
+        })
+    }
+
+    /// Whether the event is a message sent by one of the bots, e.g. echoed back by the platform
+    async fn is_own_message(&self, matcher: &Matcher) -> bool {
+        let Event::MessageEvent(event) = matcher.event.as_ref() else {
+            return false;
+        };
+        self.bot_registry
+            .is_bot_user(matcher.bot.server(), &event.sender.id)
+            .await
+    }
+
+    pub(crate) async fn dispatch(self: Arc<Self>, matcher: Matcher, mut turns: EventTurns) {
+        self.bot_registry
+            .scope(async {
+                // checked before the turn, so a bot slow to tell its id doesn't hold up the conversation
+                let own_message = self.ignore_own_messages && self.is_own_message(&matcher).await;
+                if let Some(pipeline) = turns.pipeline.as_mut() {
+                    pipeline.wait().await;
+                }
+                if own_message {
+                    return;
+                }
+                let _running = match &self.in_flight {
+                    Some(in_flight) => in_flight.running.clone().acquire_owned().await.ok(),
+                    None => None,
+                };
+                let processed = self.filter_pool.process(matcher).await;
+                if self.ordering == OrderingMode::Ordered {
+                    // let the next event of the conversation enter the filters
+                    turns.pipeline.take();
+                }
+                if let Some(matcher) = processed {
+                    self.handler_pool
+                        .handle(matcher, turns.handlers.take())
+                        .await;
+                }
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        channel::{event_channel, ChannelConfig},
+        extensions::Extensions,
+        intercept::Interceptors,
+        testing::message,
+    };
+
+    fn dispatcher(ordering: OrderingMode, in_flight: Option<InFlight>) -> Dispatcher {
+        let (sender, _, _) = event_channel(
+            &ChannelConfig::default(),
+            Extensions::default(),
+            Interceptors::default(),
+        );
+        Dispatcher::new(
+            EventHandlerPool::new(),
+            FilterPool::new(),
+            BotRegistry::new(sender),
+            ordering,
+            false,
+            in_flight,
+        )
+    }
+
+    async fn passes(turn: &mut Turn) -> bool {
+        tokio::time::timeout(Duration::from_millis(20), turn.wait())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn turns_pass_in_order() {
+        let mut last = passed_turn();
+        let mut first = Turn::chain(&mut last);
+        let mut second = Turn::chain(&mut last);
+        let mut third = Turn::chain(&mut last);
+        assert!(passes(&mut first).await);
+        assert!(!passes(&mut second).await);
+        drop(first);
+        assert!(passes(&mut second).await);
+        assert!(!passes(&mut third).await);
+        drop(second);
+        assert!(passes(&mut third).await);
+    }
+
+    #[tokio::test]
+    async fn conversations_take_separate_turns() {
+        let dispatcher = dispatcher(OrderingMode::Serialized, None);
+        let first = dispatcher.prepare(&message(Some("a"), "1", "x")).await;
+        let mut same = dispatcher.prepare(&message(Some("a"), "2", "x")).await;
+        let mut other = dispatcher.prepare(&message(Some("b"), "1", "x")).await;
+        let mut private = dispatcher.prepare(&message(None, "1", "x")).await;
+        assert!(!passes(same.pipeline.as_mut().unwrap()).await);
+        assert!(passes(other.pipeline.as_mut().unwrap()).await);
+        assert!(passes(private.pipeline.as_mut().unwrap()).await);
+        drop(first);
+        assert!(passes(same.pipeline.as_mut().unwrap()).await);
+        // the handlers are ordered by the pipeline Turn
+        assert!(same.handlers.is_none());
+    }
+
+    #[tokio::test]
+    async fn unordered_takes_no_turns() {
+        let dispatcher = dispatcher(OrderingMode::Unordered, None);
+        let turns = dispatcher.prepare(&message(Some("a"), "1", "x")).await;
+        assert!(turns.pipeline.is_none() && turns.handlers.is_none());
+    }
+
+    #[tokio::test]
+    async fn busy_conversation_only_waits_for_itself() {
+        let dispatcher = dispatcher(OrderingMode::Serialized, Some(InFlight::new(2)));
+        let busy = message(Some("a"), "1", "x");
+        let _first = dispatcher.prepare(&busy).await;
+        let _second = dispatcher.prepare(&busy).await;
+        let third = tokio::time::timeout(Duration::from_millis(20), dispatcher.prepare(&busy));
+        assert!(third.await.is_err());
+        let other = message(Some("b"), "1", "x");
+        let other = tokio::time::timeout(Duration::from_millis(20), dispatcher.prepare(&other));
+        assert!(other.await.is_ok());
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    fmt,
+    future::Future,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+
+use crate::{matcher::Matcher, source::message::MessageSegment};
+
+/// HandlerFailure is the way an event handler failed to handle an event
+#[derive(Debug)]
+pub enum HandlerFailure {
+    /// The event handler returned an error
+    Error(anyhow::Error),
+    /// The event handler panicked, with the panic message
+    Panic(String),
+    /// The event handler ran longer than its `HandlerLimits::timeout` and was cancelled
+    Timeout(Duration),
+}
+
+impl fmt::Display for HandlerFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerFailure::Error(e) => write!(f, "error: {:?}", e),
+            HandlerFailure::Panic(message) => write!(f, "panicked: {}", message),
+            HandlerFailure::Timeout(timeout) => write!(f, "timed out after {:?}", timeout),
+        }
+    }
+}
+
+/// HandlerError describes an event handler failing to handle an event.
+/// The correlation id is also written to the log entry, so a user reporting it can be matched with the log.
+#[derive(Debug)]
+pub struct HandlerError {
+    /// The name of the event handler, see `EventHandlerTrait::name`
+    pub handler: String,
+    pub correlation_id: String,
+    pub failure: HandlerFailure,
+}
+
+static NEXT_CORRELATION_ID: AtomicU64 = AtomicU64::new(0);
+
+impl HandlerError {
+    pub(crate) fn new(handler: &str, failure: HandlerFailure) -> Self {
+        let sequence = NEXT_CORRELATION_ID.fetch_add(1, Ordering::Relaxed);
+        HandlerError {
+            handler: handler.to_string(),
+            correlation_id: format!("{:x}-{:x}", chrono::Utc::now().timestamp(), sequence),
+            failure,
+        }
+    }
+}
+
+/// CatchPanic resolves to the output of the future, or to the message of its panic
+pub(crate) struct CatchPanic<F>(Pin<Box<F>>);
+
+impl<F: Future> CatchPanic<F> {
+    pub(crate) fn new(future: F) -> Self {
+        CatchPanic(Box::pin(future))
+    }
+}
+
+impl<F: Future> Future for CatchPanic<F> {
+    type Output = Result<F::Output, String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = self.0.as_mut();
+        match std::panic::catch_unwind(AssertUnwindSafe(|| future.poll(cx))) {
+            Ok(poll) => poll.map(Ok),
+            Err(panic) => Poll::Ready(Err(panic_message(panic))),
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+/// Error hook is called after an event handler failed to handle an event, e.g. to report it or reply to the user.
+/// The failure is always logged with its correlation id before the error hooks are called.
+#[async_trait]
+pub trait ErrorHookTrait: Send + Sync {
+    async fn on_error(&self, error: &HandlerError, matcher: Matcher);
+}
+
+pub type ErrorHookObject = Box<dyn ErrorHookTrait>;
+
+impl<E: ErrorHookTrait + 'static> From<E> for ErrorHookObject {
+    fn from(error_hook: E) -> Self {
+        Box::new(error_hook)
+    }
+}
+
+/// ReplyOnError is a built-in error hook replying to the user with a short message and the correlation id
+pub struct ReplyOnError {
+    pub message: String,
+}
+
+impl Default for ReplyOnError {
+    fn default() -> Self {
+        ReplyOnError {
+            message: "Something went wrong while handling your message.".to_string(),
+        }
+    }
+}
+
+impl ReplyOnError {
+    pub fn new<T: Into<String>>(message: T) -> Self {
+        ReplyOnError {
+            message: message.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl ErrorHookTrait for ReplyOnError {
+    async fn on_error(&self, error: &HandlerError, matcher: Matcher) {
+        let reply = format!("{} (error id: {})", self.message, error.correlation_id);
+        if let Err(e) = matcher
+            .try_send_message(vec![MessageSegment::text(reply)])
+            .await
+        {
+            tracing::debug!(
+                "Failed to reply the error {} to the user: {:?}",
+                error.correlation_id,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{
+        concurrency::HandlerLimits,
+        handler::{EventHandlerPool, EventHandlerTrait, Handler, Propagation},
+        testing::{message_to, TestBot},
+    };
+
+    /// An event handler failing the way the message says: with an error, a panic, or by running for too long
+    struct Failing;
+
+    #[async_trait]
+    impl EventHandlerTrait for Failing {
+        async fn handle_propagation(&self, matcher: Matcher) -> Result<Propagation> {
+            match matcher.try_get_message().unwrap().get_raw_text().as_str() {
+                "error" => Err(anyhow::anyhow!("failed")),
+                "panic" => panic!("boom"),
+                _ => {
+                    tokio::time::sleep(Duration::from_secs(3600)).await;
+                    Ok(Propagation::Continue)
+                }
+            }
+        }
+
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        fn limits(&self) -> HandlerLimits {
+            HandlerLimits::default().timeout(Duration::from_millis(10))
+        }
+    }
+
+    /// An error hook keeping the errors it was called with
+    #[derive(Clone, Default)]
+    struct Errors(Arc<Mutex<Vec<(String, String, String)>>>);
+
+    #[async_trait]
+    impl ErrorHookTrait for Errors {
+        async fn on_error(&self, error: &HandlerError, _matcher: Matcher) {
+            self.0.lock().unwrap().push((
+                error.handler.clone(),
+                error.correlation_id.clone(),
+                error.failure.to_string(),
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn error_hooks_receive_errors_panics_and_timeouts() {
+        let bot = TestBot::new("bot");
+        let errors = Errors::default();
+        let mut pool = EventHandlerPool::new();
+        pool.add_handler(Handler::event(Failing));
+        pool.add_error_hook(errors.clone().into());
+        pool.add_error_hook(ReplyOnError::new("Oops").into());
+
+        for text in ["error", "panic", "sleep"] {
+            pool.handle(message_to(&bot, Some("g"), "1", text), None)
+                .await;
+        }
+
+        let errors = errors.0.lock().unwrap().clone();
+        let failures = errors
+            .iter()
+            .map(|(handler, _, failure)| (handler.as_str(), failure.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(failures[0].0, "failing");
+        assert!(failures[0].1.starts_with("error: failed"));
+        assert_eq!(failures[1].1, "panicked: boom");
+        assert_eq!(failures[2].1, "timed out after 10ms");
+
+        // the replies tell the correlation id of the log entry
+        let sent = bot.sent.lock().unwrap();
+        assert_eq!(sent.len(), 3);
+        for ((_, correlation_id, _), (_, message)) in errors.iter().zip(sent.iter()) {
+            assert_eq!(
+                message,
+                &[MessageSegment::text(format!(
+                    "Oops (error id: {})",
+                    correlation_id
+                ))]
+            );
+        }
+        assert_ne!(errors[0].1, errors[1].1);
+    }
+}
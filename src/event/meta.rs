@@ -1,6 +1,39 @@
+use std::any::Any;
+
+use super::{Event, EventObject, EventTrait};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum MetaEvent {
     ConnectEvent,
     DisconnectEvent,
+    /// Events were dropped because the OxideBotManager couldn't keep up with the bots
+    LaggedEvent {
+        skipped: u64,
+    },
+}
+
+/// MetaEventObject is a EventObject raised by oxidebot itself instead of a bot,
+/// e.g. when the `start_sending_events` task of a bot stopped or restarted.
+#[derive(Debug, Clone)]
+pub struct MetaEventObject {
+    pub server: &'static str,
+    pub event: MetaEvent,
+}
+
+impl EventTrait for MetaEventObject {
+    fn get_events(&self) -> Vec<Event> {
+        vec![Event::MetaEvent(self.event.clone())]
+    }
+
+    fn server(&self) -> &'static str {
+        self.server
+    }
+
+    fn clone_box(&self) -> EventObject {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
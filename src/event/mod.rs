@@ -13,6 +13,7 @@ pub mod notice;
 pub mod request;
 
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum Event {
     MessageEvent(MessageEvent),
     NoticeEvent(NoticeEvent),
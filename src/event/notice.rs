@@ -12,6 +12,7 @@ use crate::{
 };
 
 #[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::large_enum_variant)]
 pub enum NoticeEvent {
     GroupMemberIncreseEvent(GroupMemberIncreseEvent),
     GroupMemberDecreaseEvent(GroupMemberDecreaseEvent),
@@ -157,6 +158,7 @@ pub struct MessageDeletedEvent {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::large_enum_variant)]
 pub enum GroupMemberIncreseReason {
     Approve {
         operator: Option<User>,
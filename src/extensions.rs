@@ -0,0 +1,135 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+type Extension = Arc<dyn Any + Send + Sync>;
+
+/// Extensions is a map of shared values keyed by their type, e.g. a database pool, an http client or a config.
+/// Insert them with `OxideBotManager::extension`, and get them in the handlers with `Matcher::extension` or the `Ext<T>` extractor.
+/// It's a costless cloneable handle, the values inserted later are seen by every clone.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    values: Arc<RwLock<HashMap<TypeId, Extension>>>,
+}
+
+impl Extensions {
+    /// Insert the value, replacing and returning the one of the same type if any
+    pub fn insert<T: Send + Sync + 'static>(&self, value: T) -> Option<Arc<T>> {
+        self.values
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(TypeId::of::<T>(), Arc::new(value))
+            .and_then(|old| old.downcast().ok())
+    }
+
+    /// Get the value of the type
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.values
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&TypeId::of::<T>())
+            .cloned()
+            .and_then(|value| value.downcast().ok())
+    }
+
+    /// Whether there is a value of the type
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.values
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains_key(&TypeId::of::<T>())
+    }
+
+    /// Remove and return the value of the type
+    pub fn remove<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.values
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+    }
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let len = self.values.read().unwrap_or_else(|e| e.into_inner()).len();
+        f.debug_struct("Extensions").field("len", &len).finish()
+    }
+}
+
+/// EventContext is a map of values attached to one event, e.g. by the middlewares, keyed by their type.
+/// Unlike Extensions, every clone of it is independent: the handlers and waiters receiving a clone of the Matcher
+/// don't see the values the others insert. The values are only copied when a shared map is changed.
+#[derive(Clone, Default)]
+pub struct EventContext {
+    values: Arc<HashMap<TypeId, Extension>>,
+}
+
+impl EventContext {
+    /// Insert the value, replacing and returning the one of the same type if any
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<Arc<T>> {
+        Arc::make_mut(&mut self.values)
+            .insert(TypeId::of::<T>(), Arc::new(value))
+            .and_then(|old| old.downcast().ok())
+    }
+
+    /// Get the value of the type
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .cloned()
+            .and_then(|value| value.downcast().ok())
+    }
+
+    /// Whether there is a value of the type
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<T>())
+    }
+
+    /// Remove and return the value of the type
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<Arc<T>> {
+        if !self.contains::<T>() {
+            return None;
+        }
+        Arc::make_mut(&mut self.values)
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+    }
+}
+
+impl std::fmt::Debug for EventContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventContext")
+            .field("len", &self.values.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_contexts_are_copied_on_write() {
+        let mut first = EventContext::default();
+        first.insert(1u8);
+        let mut second = first.clone();
+        second.insert(2u8);
+        second.insert("only in the second");
+        assert_eq!(first.get::<u8>().as_deref(), Some(&1));
+        assert!(!first.contains::<&str>());
+        assert_eq!(second.get::<u8>().as_deref(), Some(&2));
+        assert_eq!(second.remove::<u8>().as_deref(), Some(&2));
+        assert_eq!(first.get::<u8>().as_deref(), Some(&1));
+    }
+
+    #[test]
+    fn extensions_are_shared() {
+        let first = Extensions::default();
+        let second = first.clone();
+        second.insert(1u8);
+        assert_eq!(first.get::<u8>().as_deref(), Some(&1));
+    }
+}
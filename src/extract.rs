@@ -0,0 +1,405 @@
+use std::{future::Future, marker::PhantomData, pin::Pin, str::FromStr, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{
+    bot::BotObject,
+    concurrency::HandlerLimits,
+    event::{Event, MessageEvent},
+    handler::{EventHandlerTrait, Handler, Propagation, DEFAULT_PRIORITY},
+    help::HandlerMetadata,
+    matcher::Matcher,
+    source::{group::Group, message::Message, user::User},
+};
+
+/// FromMatcher extracts a typed argument of an extractor handler from the Matcher.
+/// `S` is the state given to `handler_with_state`, it's `()` for the handlers created with `handler`.
+pub trait FromMatcher<S = ()>: Sized {
+    /// Extract the argument, None if it doesn't apply to the event, then the handler isn't called.
+    fn from_matcher(matcher: &Matcher, state: &S) -> Option<Self>;
+}
+
+impl<S> FromMatcher<S> for Matcher {
+    fn from_matcher(matcher: &Matcher, _state: &S) -> Option<Self> {
+        Some(matcher.clone())
+    }
+}
+
+impl<S> FromMatcher<S> for BotObject {
+    fn from_matcher(matcher: &Matcher, _state: &S) -> Option<Self> {
+        Some(matcher.bot.clone())
+    }
+}
+
+impl<S> FromMatcher<S> for MessageEvent {
+    fn from_matcher(matcher: &Matcher, _state: &S) -> Option<Self> {
+        match matcher.event.as_ref() {
+            Event::MessageEvent(event) => Some(event.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl<S> FromMatcher<S> for Message {
+    fn from_matcher(matcher: &Matcher, _state: &S) -> Option<Self> {
+        matcher.try_get_message().cloned()
+    }
+}
+
+impl<S> FromMatcher<S> for User {
+    fn from_matcher(matcher: &Matcher, _state: &S) -> Option<Self> {
+        matcher.try_get_user().cloned()
+    }
+}
+
+impl<S> FromMatcher<S> for Group {
+    fn from_matcher(matcher: &Matcher, _state: &S) -> Option<Self> {
+        matcher.try_get_group().cloned()
+    }
+}
+
+/// An optional argument never stops the handler from being called, e.g. `Option<Group>` for a handler of both group and private messages
+impl<S, T: FromMatcher<S>> FromMatcher<S> for Option<T> {
+    fn from_matcher(matcher: &Matcher, state: &S) -> Option<Self> {
+        Some(T::from_matcher(matcher, state))
+    }
+}
+
+/// State extracts a clone of the state given to `handler_with_state`
+#[derive(Clone, Debug)]
+pub struct State<S>(pub S);
+
+impl<S: Clone> FromMatcher<S> for State<S> {
+    fn from_matcher(_matcher: &Matcher, state: &S) -> Option<Self> {
+        Some(State(state.clone()))
+    }
+}
+
+/// Ext extracts the value of the type inserted with `OxideBotManager::extension`.
+/// The handler isn't called if there is no such value, use `Option<Ext<T>>` for an optional one.
+#[derive(Debug)]
+pub struct Ext<T>(pub Arc<T>);
+
+impl<T> Clone for Ext<T> {
+    fn clone(&self) -> Self {
+        Ext(self.0.clone())
+    }
+}
+
+impl<T> std::ops::Deref for Ext<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<S, T: Send + Sync + 'static> FromMatcher<S> for Ext<T> {
+    fn from_matcher(matcher: &Matcher, _state: &S) -> Option<Self> {
+        matcher.extension::<T>().map(Ext)
+    }
+}
+
+/// Text extracts the plain text of the message
+#[derive(Clone, Debug)]
+pub struct Text(pub String);
+
+impl<S> FromMatcher<S> for Text {
+    fn from_matcher(matcher: &Matcher, _state: &S) -> Option<Self> {
+        matcher
+            .try_get_message()
+            .map(|message| Text(message.get_raw_text()))
+    }
+}
+
+/// Args extracts the whitespace separated words following the first word (the command) of the message,
+/// e.g. `Args<(String, u32)>` for `/roll dice 6`.
+#[derive(Clone, Debug)]
+pub struct Args<T>(pub T);
+
+/// FromArgs parses the words following the command
+pub trait FromArgs: Sized {
+    fn from_args(args: &[&str]) -> Option<Self>;
+}
+
+/// Any number of words
+impl FromArgs for Vec<String> {
+    fn from_args(args: &[&str]) -> Option<Self> {
+        Some(args.iter().map(|arg| arg.to_string()).collect())
+    }
+}
+
+macro_rules! impl_from_args {
+    ($count:literal: $($ty:ident),*) => {
+        /// Exactly as many words as the tuple has elements, each parsed with FromStr
+        #[allow(non_snake_case)]
+        impl<$($ty: FromStr,)*> FromArgs for ($($ty,)*) {
+            fn from_args(args: &[&str]) -> Option<Self> {
+                let [$($ty,)*] = <[&str; $count]>::try_from(args).ok()?;
+                Some(($($ty.parse().ok()?,)*))
+            }
+        }
+    };
+}
+
+impl_from_args!(1: T1);
+impl_from_args!(2: T1, T2);
+impl_from_args!(3: T1, T2, T3);
+impl_from_args!(4: T1, T2, T3, T4);
+impl_from_args!(5: T1, T2, T3, T4, T5);
+impl_from_args!(6: T1, T2, T3, T4, T5, T6);
+
+impl<S, T: FromArgs> FromMatcher<S> for Args<T> {
+    fn from_matcher(matcher: &Matcher, _state: &S) -> Option<Self> {
+        let text = matcher.try_get_message()?.get_raw_text();
+        let args = text.split_whitespace().skip(1).collect::<Vec<_>>();
+        T::from_args(&args).map(Args)
+    }
+}
+
+type HandlerFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// ExtractHandler is implemented by the async functions and closures whose arguments all implement FromMatcher.
+/// `T` is the tuple of the argument types.
+pub trait ExtractHandler<T, S>: Send + Sync + 'static {
+    /// Extract the arguments and call the handler, None if any of the arguments doesn't apply to the event.
+    fn call(&self, matcher: &Matcher, state: &S) -> Option<HandlerFuture>;
+}
+
+macro_rules! impl_extract_handler {
+    ($($ty:ident),*) => {
+        #[allow(non_snake_case, unused_variables)]
+        impl<F, Fut, S, $($ty,)*> ExtractHandler<($($ty,)*), S> for F
+        where
+            F: Fn($($ty),*) -> Fut + Send + Sync + 'static,
+            Fut: Future<Output = Result<()>> + Send + 'static,
+            $($ty: FromMatcher<S>,)*
+        {
+            fn call(&self, matcher: &Matcher, state: &S) -> Option<HandlerFuture> {
+                $(let $ty = <$ty as FromMatcher<S>>::from_matcher(matcher, state)?;)*
+                Some(Box::pin(self($($ty),*)))
+            }
+        }
+    };
+}
+
+impl_extract_handler!();
+impl_extract_handler!(T1);
+impl_extract_handler!(T1, T2);
+impl_extract_handler!(T1, T2, T3);
+impl_extract_handler!(T1, T2, T3, T4);
+impl_extract_handler!(T1, T2, T3, T4, T5);
+impl_extract_handler!(T1, T2, T3, T4, T5, T6);
+impl_extract_handler!(T1, T2, T3, T4, T5, T6, T7);
+impl_extract_handler!(T1, T2, T3, T4, T5, T6, T7, T8);
+
+/// Extract is the event handler calling an ExtractHandler, created with `handler` or `handler_with_state`.
+/// Events that the arguments don't apply to are ignored.
+pub struct Extract<H, T, S = ()> {
+    handler: H,
+    state: S,
+    name: Option<String>,
+    priority: u8,
+    limits: HandlerLimits,
+    metadata: Vec<HandlerMetadata>,
+    _arguments: PhantomData<fn() -> T>,
+}
+
+impl<H, T, S> Extract<H, T, S> {
+    /// Set the name used in the logs, the type name of the function by default
+    pub fn with_name<N: Into<String>>(mut self, name: N) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: HandlerLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Add the HandlerMetadata of a command or feature of the handler, listed by the help command
+    pub fn with_metadata(mut self, metadata: HandlerMetadata) -> Self {
+        self.metadata.push(metadata);
+        self
+    }
+}
+
+#[async_trait]
+impl<H, T, S> EventHandlerTrait for Extract<H, T, S>
+where
+    H: ExtractHandler<T, S>,
+    T: 'static,
+    S: Send + Sync + 'static,
+{
+    async fn handle_propagation(&self, matcher: Matcher) -> Result<Propagation> {
+        match self.handler.call(&matcher, &self.state) {
+            Some(handling) => handling.await.map(|_| Propagation::Continue),
+            None => Ok(Propagation::Continue),
+        }
+    }
+
+    fn get_priority(&self) -> u8 {
+        self.priority
+    }
+
+    fn name(&self) -> &str {
+        self.name
+            .as_deref()
+            .unwrap_or_else(|| std::any::type_name::<H>())
+    }
+
+    fn metadata(&self) -> Vec<HandlerMetadata> {
+        self.metadata.clone()
+    }
+
+    fn limits(&self) -> HandlerLimits {
+        self.limits.clone()
+    }
+}
+
+impl<H, T, S> From<Extract<H, T, S>> for Handler
+where
+    H: ExtractHandler<T, S>,
+    T: 'static,
+    S: Send + Sync + 'static,
+{
+    fn from(extract: Extract<H, T, S>) -> Self {
+        Handler::event(extract)
+    }
+}
+
+/// Create an event handler from an async function or closure taking extractors, e.g. `async fn(User, Text) -> Result<()>`
+pub fn handler<H, T>(handler: H) -> Extract<H, T>
+where
+    H: ExtractHandler<T, ()>,
+{
+    handler_with_state(handler, ())
+}
+
+/// Create an event handler from an async function or closure taking extractors, which can extract a clone of `state` with `State<S>`
+pub fn handler_with_state<H, T, S>(handler: H, state: S) -> Extract<H, T, S>
+where
+    H: ExtractHandler<T, S>,
+{
+    Extract {
+        handler,
+        state,
+        name: None,
+        priority: DEFAULT_PRIORITY,
+        limits: HandlerLimits::default(),
+        metadata: Vec::new(),
+        _arguments: PhantomData,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::testing::message;
+
+    type Calls = Arc<Mutex<Vec<(String, Option<String>, u32, String, String)>>>;
+
+    struct Greeting {
+        text: &'static str,
+    }
+
+    type Arguments = (User, Option<Group>, Args<(u32,)>, Ext<Greeting>, Text);
+
+    fn roll(calls: Calls) -> Extract<impl ExtractHandler<Arguments, ()>, Arguments> {
+        handler(
+            move |user: User,
+                  group: Option<Group>,
+                  Args((sides,)): Args<(u32,)>,
+                  greeting: Ext<Greeting>,
+                  Text(text): Text| {
+                let calls = calls.clone();
+                async move {
+                    calls.lock().unwrap().push((
+                        user.id,
+                        group.map(|group| group.id),
+                        sides,
+                        greeting.text.to_string(),
+                        text,
+                    ));
+                    Ok(())
+                }
+            },
+        )
+    }
+
+    fn with_greeting(matcher: Matcher) -> Matcher {
+        matcher.extensions().insert(Greeting { text: "hello" });
+        matcher
+    }
+
+    #[tokio::test]
+    async fn handlers_get_the_extracted_arguments() {
+        let calls = Calls::default();
+        let roll = roll(calls.clone());
+        for matcher in [
+            with_greeting(message(Some("g"), "1", "/roll 6")),
+            with_greeting(message(None, "2", "/roll 20")),
+        ] {
+            let propagation = roll.handle_propagation(matcher).await.unwrap();
+            assert_eq!(propagation, Propagation::Continue);
+        }
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                (
+                    "1".to_string(),
+                    Some("g".to_string()),
+                    6,
+                    "hello".to_string(),
+                    "/roll 6".to_string()
+                ),
+                (
+                    "2".to_string(),
+                    None,
+                    20,
+                    "hello".to_string(),
+                    "/roll 20".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn handlers_are_skipped_when_an_argument_does_not_apply() {
+        let calls = Calls::default();
+        let roll = roll(calls.clone());
+        // the argument isn't a number, there are too many arguments, or there is no Greeting extension
+        for matcher in [
+            with_greeting(message(Some("g"), "1", "/roll six")),
+            with_greeting(message(Some("g"), "1", "/roll 6 6")),
+            message(Some("g"), "1", "/roll 6"),
+        ] {
+            let propagation = roll.handle_propagation(matcher).await.unwrap();
+            assert_eq!(propagation, Propagation::Continue);
+        }
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn settings_are_forwarded() {
+        let limits = HandlerLimits::default().max_concurrency(1);
+        let extract = roll(Calls::default())
+            .with_name("roll")
+            .priority(3)
+            .with_limits(limits.clone())
+            .with_metadata(HandlerMetadata::new("roll").usage("roll <sides>"));
+        assert_eq!(extract.name(), "roll");
+        assert_eq!(extract.get_priority(), 3);
+        assert_eq!(extract.limits(), limits);
+        assert_eq!(extract.metadata()[0].name, "roll");
+        assert!(roll(Calls::default()).name().contains("roll"));
+    }
+}
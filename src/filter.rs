@@ -1,6 +1,18 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
 use async_trait::async_trait;
+use tokio::task::JoinSet;
 
-use crate::matcher::Matcher;
+use crate::{
+    bot::{current_registry, scope_registry},
+    handler::DEFAULT_PRIORITY,
+    matcher::Matcher,
+    source::message::MessageSegment,
+};
 
 /// Filter runs before the Handler, allowing it to process the event and decide whether the event should continue to be handled by the Handler.
 /// The Filter runs in order of priority, from low to high and stops when one of the Filters returns false.
@@ -11,39 +23,525 @@ pub trait FilterTrait: Send + Sync {
     async fn filter(&self, matcher: Matcher) -> bool;
     /// Get the priority of the Filter.
     fn get_priority(&self) -> u8;
+    /// Get the name of the Filter, used in the logs.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+    /// Get the timeout of the Filter, None means the `FilterConfig::timeout` is used.
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+    /// Called once the event passed all the Filters and Middlewares, e.g. to start the cooldown that `filter` only checked.
+    async fn passed(&self, _matcher: &Matcher) {}
 }
 
 pub type FilterObject = Box<dyn FilterTrait>;
 
+/// Any `Fn(Matcher) -> impl Future<Output = bool>` closure is a Filter with the `DEFAULT_PRIORITY`
+#[async_trait]
+impl<F, Fut> FilterTrait for F
+where
+    F: Fn(Matcher) -> Fut + Send + Sync,
+    Fut: Future<Output = bool> + Send + 'static,
+{
+    async fn filter(&self, matcher: Matcher) -> bool {
+        self(matcher).await
+    }
+
+    fn get_priority(&self) -> u8 {
+        DEFAULT_PRIORITY
+    }
+}
+
+impl<F: FilterTrait + 'static> From<F> for FilterObject {
+    fn from(filter: F) -> Self {
+        Box::new(filter)
+    }
+}
+
+/// MiddlewareOutcome is what a Middleware decided for the event
+#[derive(Clone, Debug)]
+pub enum MiddlewareOutcome {
+    /// Pass the Matcher, possibly enriched or rewritten, to the next Filters and Middlewares and then to the Handlers
+    Next(Matcher),
+    /// Drop the event
+    Reject,
+    /// Drop the event and reply the message to the user
+    Reply(Vec<MessageSegment>),
+}
+
+/// Middleware runs among the Filters in order of priority, but instead of only deciding whether the event continues,
+/// it can attach data to the event with `Matcher::context`, rewrite it with `Matcher::event_mut`,
+/// or short-circuit with a reply. The Matcher it returns is the one the following Filters, Middlewares and Handlers see.
+/// The waiters (e.g. `wait_user`) still see the event as it was sent by the bot.
+#[async_trait]
+pub trait MiddlewareTrait: Send + Sync {
+    /// Process the event with the Matcher.
+    async fn process(&self, matcher: Matcher) -> MiddlewareOutcome;
+    /// Get the priority of the Middleware, it's ordered together with the Filters.
+    fn get_priority(&self) -> u8;
+    /// Get the name of the Middleware, used in the logs.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+    /// Get the timeout of the Middleware, None means the `FilterConfig::timeout` is used.
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+}
+
+pub type MiddlewareObject = Box<dyn MiddlewareTrait>;
+
+/// Any `Fn(Matcher) -> impl Future<Output = MiddlewareOutcome>` closure is a Middleware with the `DEFAULT_PRIORITY`
+#[async_trait]
+impl<F, Fut> MiddlewareTrait for F
+where
+    F: Fn(Matcher) -> Fut + Send + Sync,
+    Fut: Future<Output = MiddlewareOutcome> + Send + 'static,
+{
+    async fn process(&self, matcher: Matcher) -> MiddlewareOutcome {
+        self(matcher).await
+    }
+
+    fn get_priority(&self) -> u8 {
+        DEFAULT_PRIORITY
+    }
+}
+
+impl<M: MiddlewareTrait + 'static> From<M> for MiddlewareObject {
+    fn from(middleware: M) -> Self {
+        Box::new(middleware)
+    }
+}
+
+/// FilterFallback is the decision made for a Filter that panicked or timed out
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum FilterFallback {
+    /// Let the event pass the Filter
+    Allow,
+    /// Drop the event
+    #[default]
+    Deny,
+}
+
+impl FilterFallback {
+    fn passed(self) -> bool {
+        self == FilterFallback::Allow
+    }
+}
+
+/// FilterConfig decides how the Filters are isolated from each other
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterConfig {
+    /// The default timeout of a Filter
+    pub timeout: Duration,
+    /// A Filter running longer than this is reported as slow
+    pub slow_threshold: Duration,
+    /// The decision made when a Filter panics
+    pub on_panic: FilterFallback,
+    /// The decision made when a Filter times out
+    pub on_timeout: FilterFallback,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            timeout: Duration::from_secs(10),
+            slow_threshold: Duration::from_secs(1),
+            on_panic: FilterFallback::Deny,
+            on_timeout: FilterFallback::Deny,
+        }
+    }
+}
+
+/// A step of the FilterPool
+#[derive(Clone)]
+enum Stage {
+    Filter(Arc<FilterObject>),
+    Middleware(Arc<MiddlewareObject>),
+}
+
+impl Stage {
+    fn priority(&self) -> u8 {
+        match self {
+            Stage::Filter(filter) => filter.get_priority(),
+            Stage::Middleware(middleware) => middleware.get_priority(),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Stage::Filter(filter) => filter.name(),
+            Stage::Middleware(middleware) => middleware.name(),
+        }
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        match self {
+            Stage::Filter(filter) => filter.timeout(),
+            Stage::Middleware(middleware) => middleware.timeout(),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Stage::Filter(_) => "Filter",
+            Stage::Middleware(_) => "Middleware",
+        }
+    }
+
+    async fn run(self, matcher: Matcher) -> MiddlewareOutcome {
+        match self {
+            Stage::Filter(filter) => match filter.filter(matcher.clone()).await {
+                true => MiddlewareOutcome::Next(matcher),
+                false => MiddlewareOutcome::Reject,
+            },
+            Stage::Middleware(middleware) => middleware.process(matcher).await,
+        }
+    }
+}
+
 pub struct FilterPool {
-    filters: Vec<FilterObject>,
+    stages: Vec<Stage>,
+    config: FilterConfig,
+}
+
+impl Default for FilterPool {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FilterPool {
     pub fn new() -> Self {
         FilterPool {
-            filters: Vec::new(),
+            stages: Vec::new(),
+            config: FilterConfig::default(),
         }
     }
 
     pub fn build(filters: Vec<FilterObject>) -> Self {
-        let mut filters = filters;
-        filters.sort_by_key(|filter| filter.get_priority());
-
-        FilterPool { filters }
+        let mut pool = Self::new();
+        for filter in filters {
+            pool.add_filter(filter);
+        }
+        pool
     }
 
     pub fn add_filter(&mut self, filter: FilterObject) {
-        self.filters.push(filter);
-        self.filters.sort_by_key(|filter| filter.get_priority());
+        self.add_stage(Stage::Filter(Arc::new(filter)));
+    }
+
+    pub fn add_middleware(&mut self, middleware: MiddlewareObject) {
+        self.add_stage(Stage::Middleware(Arc::new(middleware)));
+    }
+
+    fn add_stage(&mut self, stage: Stage) {
+        self.stages.push(stage);
+        self.stages.sort_by_key(|stage| stage.priority());
+    }
+
+    pub fn set_config(&mut self, config: FilterConfig) {
+        self.config = config;
     }
 
     pub async fn filter(&self, matcher: Matcher) -> bool {
-        for filter in &self.filters {
-            if !filter.filter(matcher.clone()).await {
-                return false;
+        self.process(matcher).await.is_some()
+    }
+
+    /// Run the Filters and Middlewares, returning the Matcher for the Handlers, or None if the event was stopped.
+    pub async fn process(&self, mut matcher: Matcher) -> Option<Matcher> {
+        for stage in &self.stages {
+            match self.run_stage(stage, matcher.clone()).await {
+                MiddlewareOutcome::Next(next) => matcher = next,
+                MiddlewareOutcome::Reject => return None,
+                MiddlewareOutcome::Reply(message) => {
+                    if let Err(e) = matcher.try_send_message(message).await {
+                        tracing::warn!(
+                            "{} {} failed to reply: {:?}",
+                            stage.kind(),
+                            stage.name(),
+                            e
+                        );
+                    }
+                    return None;
+                }
+            }
+        }
+        for stage in &self.stages {
+            if let Stage::Filter(filter) = stage {
+                filter.passed(&matcher).await;
+            }
+        }
+        Some(matcher)
+    }
+
+    /// Run the Filter or Middleware in its own task, so its panic is captured and it can be bounded by the timeout.
+    async fn run_stage(&self, stage: &Stage, matcher: Matcher) -> MiddlewareOutcome {
+        let timeout = stage.timeout().unwrap_or(self.config.timeout);
+        let started = Instant::now();
+        let fallback = |fallback: FilterFallback, matcher: Matcher| match fallback.passed() {
+            true => MiddlewareOutcome::Next(matcher),
+            false => MiddlewareOutcome::Reject,
+        };
+
+        // the JoinSet aborts the task when it's dropped, e.g. on timeout
+        let mut task = JoinSet::new();
+        task.spawn(scope_registry(
+            current_registry(),
+            stage.clone().run(matcher.clone()),
+        ));
+
+        let outcome = match tokio::time::timeout(timeout, task.join_next()).await {
+            Ok(Some(Ok(outcome))) => outcome,
+            Ok(Some(Err(e))) => {
+                tracing::error!(
+                    "{} {} panicked, the event is {}: {:?}",
+                    stage.kind(),
+                    stage.name(),
+                    fallback_action(self.config.on_panic),
+                    e
+                );
+                fallback(self.config.on_panic, matcher)
+            }
+            Ok(None) => unreachable!("the JoinSet contains a task"),
+            Err(_) => {
+                tracing::error!(
+                    "{} {} timed out after {:?}, the event is {}",
+                    stage.kind(),
+                    stage.name(),
+                    timeout,
+                    fallback_action(self.config.on_timeout)
+                );
+                return fallback(self.config.on_timeout, matcher);
+            }
+        };
+
+        let elapsed = started.elapsed();
+        if elapsed >= self.config.slow_threshold {
+            tracing::warn!(
+                "{} {} is slow, it took {:?}",
+                stage.kind(),
+                stage.name(),
+                elapsed
+            );
+        }
+        outcome
+    }
+}
+
+fn fallback_action(fallback: FilterFallback) -> &'static str {
+    match fallback {
+        FilterFallback::Allow => "allowed",
+        FilterFallback::Deny => "denied",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        manager::OxideBotManager,
+        testing::{message, message_to, Logs, Recorder, TestBot},
+    };
+
+    /// A Filter letting the events pass after sleeping, or panicking
+    struct Slow {
+        sleep: Option<Duration>,
+        timeout: Option<Duration>,
+    }
+
+    #[async_trait]
+    impl FilterTrait for Slow {
+        async fn filter(&self, _matcher: Matcher) -> bool {
+            match self.sleep {
+                Some(sleep) => tokio::time::sleep(sleep).await,
+                None => panic!("filter panicked"),
             }
+            true
+        }
+
+        fn get_priority(&self) -> u8 {
+            DEFAULT_PRIORITY
         }
-        true
+
+        fn name(&self) -> &str {
+            "slow"
+        }
+
+        fn timeout(&self) -> Option<Duration> {
+            self.timeout
+        }
+    }
+
+    fn pool(
+        sleep: Option<Duration>,
+        timeout: Option<Duration>,
+        config: FilterConfig,
+    ) -> FilterPool {
+        let mut pool = FilterPool::build(vec![Box::new(Slow { sleep, timeout })]);
+        pool.set_config(config);
+        pool
+    }
+
+    #[tokio::test]
+    async fn timed_out_filters_fall_back() {
+        let forever = Some(Duration::from_secs(3600));
+        let config = FilterConfig {
+            timeout: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let matcher = message(Some("g"), "1", "hi");
+        assert!(
+            !pool(forever, None, config.clone())
+                .filter(matcher.clone())
+                .await
+        );
+        let allowing = FilterConfig {
+            on_timeout: FilterFallback::Allow,
+            ..config.clone()
+        };
+        assert!(pool(forever, None, allowing).filter(matcher.clone()).await);
+
+        // the timeout of the Filter replaces the one of the config
+        let config = FilterConfig {
+            timeout: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let own_timeout = Some(Duration::from_millis(10));
+        let (logs, _guard) = Logs::capture();
+        assert!(!pool(forever, own_timeout, config).filter(matcher).await);
+        assert_eq!(
+            logs.lines(),
+            ["ERROR Filter slow timed out after 10ms, the event is denied"]
+        );
+    }
+
+    #[tokio::test]
+    async fn panicking_filters_fall_back() {
+        let matcher = message(Some("g"), "1", "hi");
+        let (logs, _guard) = Logs::capture();
+        assert!(
+            !pool(None, None, FilterConfig::default())
+                .filter(matcher.clone())
+                .await
+        );
+        assert!(logs.lines()[0].starts_with("ERROR Filter slow panicked, the event is denied"));
+        let allowing = FilterConfig {
+            on_panic: FilterFallback::Allow,
+            ..Default::default()
+        };
+        assert!(pool(None, None, allowing).filter(matcher).await);
+    }
+
+    #[tokio::test]
+    async fn slow_filters_are_reported() {
+        let config = FilterConfig {
+            slow_threshold: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let matcher = message(Some("g"), "1", "hi");
+        let (logs, _guard) = Logs::capture();
+        assert!(
+            pool(Some(Duration::ZERO), None, config.clone())
+                .filter(matcher.clone())
+                .await
+        );
+        assert!(logs.lines().is_empty());
+        assert!(
+            pool(Some(Duration::from_millis(20)), None, config)
+                .filter(matcher)
+                .await
+        );
+        let lines = logs.lines();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("WARN Filter slow is slow, it took "));
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Locale(&'static str);
+
+    #[tokio::test]
+    async fn middleware_changes_reach_the_handlers() {
+        let bot = TestBot::new("bot");
+        let recorder = Recorder::default();
+        let manager = OxideBotManager::new()
+            .middleware(|mut matcher: Matcher| async move {
+                matcher.insert_context(Locale("fr"));
+                if let Some(message) = matcher.try_get_message_mut() {
+                    message.segments = vec![MessageSegment::text("bonjour")];
+                }
+                MiddlewareOutcome::Next(matcher)
+            })
+            .handler(recorder.handler())
+            .bot(Box::new(bot.clone()))
+            .await;
+        bot.sender()
+            .await
+            .send(message_to(&bot, Some("g"), "1", "hello"))
+            .await
+            .unwrap();
+        let handled = recorder.clone();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            manager.run_until(async move { handled.wait(2).await }, Duration::from_secs(1)),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(recorder.texts(), ["bonjour"]);
+        let handled = recorder.handled.lock().unwrap();
+        assert!(handled
+            .iter()
+            .all(|matcher| matcher.context::<Locale>().as_deref() == Some(&Locale("fr"))));
+    }
+
+    #[tokio::test]
+    async fn reply_and_reject_stop_the_pipeline() {
+        let bot = TestBot::new("bot");
+        let later = Arc::new(AtomicUsize::new(0));
+        let mut pool = FilterPool::new();
+        pool.add_middleware(
+            (|matcher: Matcher| async move {
+                match matcher.try_get_message().unwrap().get_raw_text().as_str() {
+                    "reply" => MiddlewareOutcome::Reply(vec![MessageSegment::text("stopped")]),
+                    "reject" => MiddlewareOutcome::Reject,
+                    _ => MiddlewareOutcome::Next(matcher),
+                }
+            })
+            .into(),
+        );
+        let counter = later.clone();
+        pool.add_filter(
+            (move |_matcher: Matcher| {
+                let counter = counter.clone();
+                async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    true
+                }
+            })
+            .into(),
+        );
+
+        assert!(pool
+            .process(message_to(&bot, Some("g"), "1", "reply"))
+            .await
+            .is_none());
+        assert!(pool
+            .process(message_to(&bot, Some("g"), "1", "reject"))
+            .await
+            .is_none());
+        assert_eq!(later.load(Ordering::SeqCst), 0);
+        let sent = bot.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, [MessageSegment::text("stopped")]);
+
+        assert!(pool
+            .process(message_to(&bot, Some("g"), "1", "pass"))
+            .await
+            .is_some());
+        assert_eq!(later.load(Ordering::SeqCst), 1);
     }
 }
@@ -1,80 +1,605 @@
 use anyhow::Result;
 use async_trait::async_trait;
-use std::sync::Arc;
-use tokio::task::JoinHandle;
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::task::{JoinHandle, JoinSet};
 
-use crate::matcher::Matcher;
+use crate::{
+    bot::{scope_registry, BotRegistry},
+    concurrency::{ConcurrencyLimiter, HandlerLimits},
+    dispatch::Turn,
+    error::{CatchPanic, ErrorHookObject, HandlerError, HandlerFailure},
+    help::{HandlerMetadata, HelpMenu},
+    matcher::Matcher,
+    report::{AfterHookObject, DispatchReport, HandlerOutcome, HandlerRun},
+    supervisor::{RestartPolicy, Restarts},
+};
 
 /// Active handler runs forever, and you can do something in the background at any time.
 #[async_trait]
 pub trait ActiveHandlerTrait: Send + Sync {
     async fn run_forever(&self) -> Result<()>;
+    /// Get the name of the active handler, used to query and stop it in `ActiveHandlerRegistry`.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+    /// Get the RestartPolicy of the active handler, it's never restarted by default.
+    fn restart_policy(&self) -> RestartPolicy {
+        RestartPolicy::Never
+    }
+}
+
+/// The priority of an event handler or a closure Filter that doesn't specify one
+pub const DEFAULT_PRIORITY: u8 = 128;
+
+/// Propagation decides whether an event goes on to the event handlers running after this one,
+/// i.e. those with a larger priority number
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Propagation {
+    /// Let the event handlers with a larger priority number handle the event
+    #[default]
+    Continue,
+    /// The event is consumed, the event handlers with a larger priority number won't see it
+    Stop,
 }
 
 /// Event handler only runs when an event is triggered.
+/// The event handlers run in ascending order of priority, the smallest number first:
+/// the ones with the same priority run concurrently,
+/// and the next priority only runs if none of them returned `Propagation::Stop`.
+/// An event handler that never consumes the events can implement `SimpleEventHandlerTrait` instead.
 #[async_trait]
 pub trait EventHandlerTrait: Send + Sync {
+    /// Handle the triggered event with the matcher, and decide whether it goes on to the event handlers running after this one.
+    async fn handle_propagation(&self, matcher: Matcher) -> Result<Propagation>;
+    /// Get the priority of the event handler.
+    fn get_priority(&self) -> u8 {
+        DEFAULT_PRIORITY
+    }
+    /// Get the name of the event handler, used in the logs.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+    /// Get the HandlerMetadata of the commands or features of the event handler, listed by the help command.
+    fn metadata(&self) -> Vec<HandlerMetadata> {
+        Vec::new()
+    }
+    /// Get the HandlerLimits of the event handler, it's unlimited by default.
+    fn limits(&self) -> HandlerLimits {
+        HandlerLimits::default()
+    }
+}
+
+/// Simple event handler never consumes the events, like the event handlers before `Propagation` was added.
+/// Every SimpleEventHandlerTrait is an EventHandlerTrait with the default priority, name and limits;
+/// implement EventHandlerTrait instead to change them.
+#[async_trait]
+pub trait SimpleEventHandlerTrait: Send + Sync {
     /// Handle the triggered event with the matcher.
     async fn handle(&self, matcher: Matcher) -> Result<()>;
 }
 
+#[async_trait]
+impl<H: SimpleEventHandlerTrait> EventHandlerTrait for H {
+    async fn handle_propagation(&self, matcher: Matcher) -> Result<Propagation> {
+        self.handle(matcher).await.map(|_| Propagation::Continue)
+    }
+}
+
 pub type EventHandlerObject = Box<dyn EventHandlerTrait>;
 pub type ActiveHandlerObject = Box<dyn ActiveHandlerTrait>;
 
+/// Any `Fn(Matcher) -> impl Future<Output = Result<()>>` closure is an event handler
+#[async_trait]
+impl<F, Fut> SimpleEventHandlerTrait for F
+where
+    F: Fn(Matcher) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    async fn handle(&self, matcher: Matcher) -> Result<()> {
+        self(matcher).await
+    }
+}
+
+/// Any `Fn() -> impl Future<Output = Result<()>>` closure is an active handler
+#[async_trait]
+impl<F, Fut> ActiveHandlerTrait for F
+where
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    async fn run_forever(&self) -> Result<()> {
+        self().await
+    }
+}
+
 #[derive(Default)]
 pub struct Handler {
     pub event_handler: Option<EventHandlerObject>,
     pub active_handler: Option<ActiveHandlerObject>,
 }
 
+impl Handler {
+    /// Create a Handler with only an event handler
+    pub fn event<E: EventHandlerTrait + 'static>(event_handler: E) -> Self {
+        Handler {
+            event_handler: Some(Box::new(event_handler)),
+            active_handler: None,
+        }
+    }
+
+    /// Create a Handler with only an event handler from an async closure taking a Matcher
+    pub fn from_fn<F, Fut>(event_handler: F) -> Self
+    where
+        F: Fn(Matcher) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        Handler::event(event_handler)
+    }
+
+    /// Create a Handler with only an active handler
+    pub fn active<A: ActiveHandlerTrait + 'static>(active_handler: A) -> Self {
+        Handler {
+            event_handler: None,
+            active_handler: Some(Box::new(active_handler)),
+        }
+    }
+}
+
+/// ActiveHandlerState is the state of the task running an active handler
+#[derive(Clone, Debug, PartialEq)]
+pub enum ActiveHandlerState {
+    /// `run_forever` is running
+    Running,
+    /// `run_forever` finished, and will be restarted after the backoff delay
+    Restarting { attempt: u32 },
+    /// `run_forever` returned Ok and won't be restarted
+    Finished,
+    /// `run_forever` returned an error or panicked, and won't be restarted
+    Failed,
+    /// The active handler was stopped by `ActiveHandlerRegistry::stop` or by the shutdown of OxideBotManager
+    Stopped,
+}
+
+struct ActiveHandlerEntry {
+    name: String,
+    task: JoinHandle<()>,
+    state: Arc<Mutex<ActiveHandlerState>>,
+}
+
+/// ActiveHandlerRegistry keeps the active handlers of an OxideBotManager.
+/// It's a costless cloneable handle, so you can query and stop the active handlers by name at any time.
+#[derive(Clone, Default)]
+pub struct ActiveHandlerRegistry {
+    entries: Arc<Mutex<Vec<ActiveHandlerEntry>>>,
+}
+
+impl ActiveHandlerRegistry {
+    fn push(&self, entry: ActiveHandlerEntry) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(entry);
+    }
+
+    /// Get the ActiveHandlerState of the active handler by name
+    pub fn state(&self, name: &str) -> Option<ActiveHandlerState> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| lock_state(&entry.state).clone())
+    }
+
+    /// List the names of all active handlers with their ActiveHandlerState
+    pub fn list(&self) -> Vec<(String, ActiveHandlerState)> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|entry| (entry.name.clone(), lock_state(&entry.state).clone()))
+            .collect()
+    }
+
+    /// Stop the active handlers with the name, return false if there is no such active handler.
+    /// The stopped active handlers are never restarted.
+    pub fn stop(&self, name: &str) -> bool {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut found = false;
+        for entry in entries.iter().filter(|entry| entry.name == name) {
+            entry.task.abort();
+            *lock_state(&entry.state) = ActiveHandlerState::Stopped;
+            found = true;
+        }
+        found
+    }
+
+    /// Stop all active handlers
+    pub(crate) fn stop_all(&self) {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        for entry in entries.iter() {
+            entry.task.abort();
+            *lock_state(&entry.state) = ActiveHandlerState::Stopped;
+        }
+    }
+}
+
+fn lock_state(state: &Mutex<ActiveHandlerState>) -> std::sync::MutexGuard<'_, ActiveHandlerState> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+struct EventHandlerEntry {
+    handler: EventHandlerObject,
+    priority: u8,
+    limiter: ConcurrencyLimiter,
+}
+
+impl EventHandlerEntry {
+    /// Run the event handler within its HandlerLimits, returning how long it took to handle the event,
+    /// or None if it was busy and the event was dropped.
+    /// The turn is only released after the concurrency permits were acquired, so the queue keeps the arrival order.
+    async fn run(
+        &self,
+        matcher: Matcher,
+        turn: Option<Turn>,
+    ) -> Option<(Result<Propagation, HandlerFailure>, Duration)> {
+        let permit = match turn {
+            Some(mut turn) => {
+                turn.wait().await;
+                self.limiter.acquire(&matcher).await
+            }
+            None => self.limiter.acquire(&matcher).await,
+        };
+        let Some(_permit) = permit else {
+            tracing::debug!(
+                "Event handler {} is busy, the event is dropped",
+                self.handler.name()
+            );
+            return None;
+        };
+
+        let started = Instant::now();
+        // the panic is caught here rather than by the JoinSet, so it can be reported with the matcher
+        let handling = CatchPanic::new(self.handler.handle_propagation(matcher));
+        let result = match self.limiter.timeout() {
+            Some(timeout) => match tokio::time::timeout(timeout, handling).await {
+                Ok(result) => result,
+                Err(_) => return Some((Err(HandlerFailure::Timeout(timeout)), started.elapsed())),
+            },
+            None => handling.await,
+        };
+        let result = result
+            .map_err(HandlerFailure::Panic)
+            .and_then(|result| result.map_err(HandlerFailure::Error));
+        Some((result, started.elapsed()))
+    }
+}
+
 pub struct EventHandlerPool {
-    event_handlers: Vec<Arc<EventHandlerObject>>,
-    active_handler_joinhandsles: Vec<JoinHandle<()>>,
+    event_handlers: Vec<Arc<EventHandlerEntry>>,
+    active_handlers: ActiveHandlerRegistry,
+    error_hooks: Vec<Arc<ErrorHookObject>>,
+    after_hooks: Vec<Arc<AfterHookObject>>,
+    help_menu: HelpMenu,
+    bot_registry: Option<BotRegistry>,
+}
+
+impl Default for EventHandlerPool {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl EventHandlerPool {
     pub fn new() -> Self {
         EventHandlerPool {
             event_handlers: Vec::new(),
-            active_handler_joinhandsles: Vec::new(),
+            active_handlers: ActiveHandlerRegistry::default(),
+            error_hooks: Vec::new(),
+            after_hooks: Vec::new(),
+            help_menu: HelpMenu::default(),
+            bot_registry: None,
+        }
+    }
+
+    /// Create a EventHandlerPool whose handlers look up bots in `bot_registry` with `get_bot`
+    pub fn with_bot_registry(bot_registry: BotRegistry) -> Self {
+        EventHandlerPool {
+            bot_registry: Some(bot_registry),
+            ..Self::new()
         }
     }
 
     pub fn build(handlers: Vec<Handler>) -> Self {
-        let mut pool = EventHandlerPool {
-            event_handlers: Vec::new(),
-            active_handler_joinhandsles: Vec::new(),
-        };
+        let mut pool = Self::new();
         for handler in handlers {
             pool.add_handler(handler);
         }
         pool
     }
 
+    /// Add an error hook, called in order of addition when an event handler fails
+    pub fn add_error_hook(&mut self, error_hook: ErrorHookObject) {
+        self.error_hooks.push(Arc::new(error_hook));
+    }
+
+    /// Add an after hook, called in order of addition once the event handlers finished with an event
+    pub fn add_after_hook(&mut self, after_hook: AfterHookObject) {
+        self.after_hooks.push(Arc::new(after_hook));
+    }
+
     pub fn add_handler(&mut self, handler: Handler) {
         if let Some(event_handler) = handler.event_handler {
-            self.event_handlers.push(event_handler.into());
+            self.help_menu.extend(event_handler.metadata());
+            self.event_handlers.push(Arc::new(EventHandlerEntry {
+                priority: event_handler.get_priority(),
+                limiter: ConcurrencyLimiter::new(event_handler.limits()),
+                handler: event_handler,
+            }));
+            self.event_handlers.sort_by_key(|entry| entry.priority);
         }
         if let Some(active_handler) = handler.active_handler {
-            let active_handler = Arc::new(active_handler);
-            let join_handle = tokio::spawn(async move {
-                if let Err(e) = active_handler.run_forever().await {
-                    tracing::error!("Active handler error: {:?}", e);
-                }
-            });
-            self.active_handler_joinhandsles.push(join_handle);
+            let name = active_handler.name().to_string();
+            let state = Arc::new(Mutex::new(ActiveHandlerState::Running));
+            let task = tokio::spawn(supervise_active_handler(
+                Arc::new(active_handler),
+                state.clone(),
+                self.bot_registry.clone(),
+            ));
+            self.active_handlers
+                .push(ActiveHandlerEntry { name, task, state });
         }
     }
 
-    pub fn handle(&self, matcher: Matcher) {
-        for handler in &self.event_handlers {
-            let handler = Arc::clone(handler);
-            let matcher = matcher.clone();
-            tokio::spawn(async move {
-                if let Err(e) = handler.handle(matcher).await {
-                    tracing::error!("Event handler error: {:?}", e);
-                }
-            });
+    /// Get the registry of the active handlers in this pool
+    pub fn active_handlers(&self) -> ActiveHandlerRegistry {
+        self.active_handlers.clone()
+    }
+
+    /// Get the HelpMenu of the event handlers in this pool
+    pub fn help_menu(&self) -> HelpMenu {
+        self.help_menu.clone()
+    }
+
+    pub fn event_handler_count(&self) -> usize {
+        self.event_handlers.len()
+    }
+
+    /// Run the event handlers with the matcher in order of priority, wait for them to finish, then call the after hooks.
+    /// The event handlers with the same priority run concurrently, and the next priority only runs if the event wasn't consumed.
+    /// When `turns` is given, each event handler starts only after it was started with the previous event of the conversation.
+    /// The event handlers are aborted if this future is dropped.
+    pub(crate) async fn handle(&self, matcher: Matcher, turns: Option<Vec<Turn>>) {
+        let started = Instant::now();
+        let mut report = DispatchReport::default();
+        let mut turns = turns.map(Vec::into_iter);
+        let mut handlers = self.event_handlers.iter().peekable();
+        while let Some(first) = handlers.peek() {
+            let priority = first.priority;
+            let mut event_tasks = JoinSet::new();
+            while let Some(entry) = handlers.next_if(|entry| entry.priority == priority) {
+                let entry = Arc::clone(entry);
+                let matcher = matcher.clone();
+                let turn = turns.as_mut().and_then(Iterator::next);
+                event_tasks.spawn(scope_registry(self.bot_registry.clone(), async move {
+                    let result = entry.run(matcher, turn).await;
+                    (entry, result)
+                }));
+            }
+
+            let mut propagation = Propagation::Continue;
+            while let Some(result) = event_tasks.join_next().await {
+                let (entry, result) = match result {
+                    Ok(result) => result,
+                    Err(e) => {
+                        tracing::error!("Event handler task failed: {:?}", e);
+                        continue;
+                    }
+                };
+                let (outcome, duration) = match result {
+                    Some((Ok(handled), duration)) => {
+                        if handled == Propagation::Stop {
+                            propagation = Propagation::Stop;
+                        }
+                        (HandlerOutcome::Finished(handled), duration)
+                    }
+                    Some((Err(failure), duration)) => {
+                        let error = self.report(entry.handler.name(), failure, &matcher).await;
+                        (HandlerOutcome::Failed(error), duration)
+                    }
+                    None => (HandlerOutcome::Dropped, Duration::ZERO),
+                };
+                report.runs.push(HandlerRun {
+                    handler: entry.handler.name().to_string(),
+                    priority: entry.priority,
+                    outcome,
+                    duration,
+                });
+            }
+            if propagation == Propagation::Stop {
+                break;
+            }
+        }
+
+        report.duration = started.elapsed();
+        report.replied = matcher.replied();
+        for after_hook in &self.after_hooks {
+            after_hook.after(&report, matcher.clone()).await;
         }
     }
+
+    /// Log the failure of an event handler with a correlation id, then call the error hooks with it
+    async fn report(
+        &self,
+        handler: &str,
+        failure: HandlerFailure,
+        matcher: &Matcher,
+    ) -> HandlerError {
+        let error = HandlerError::new(handler, failure);
+        tracing::error!(
+            "Event handler {} failed [{}] on {:?} event from {}: {}",
+            error.handler,
+            error.correlation_id,
+            matcher.event.as_ref(),
+            matcher.bot.server(),
+            error.failure
+        );
+        for error_hook in &self.error_hooks {
+            error_hook.on_error(&error, matcher.clone()).await;
+        }
+        error
+    }
+
+    /// Stop all active handlers
+    pub fn stop_active_handlers(&self) {
+        self.active_handlers.stop_all();
+    }
+}
+
+/// Run `run_forever` of the active handler, capture its errors and panics, and restart it with its RestartPolicy.
+async fn supervise_active_handler(
+    active_handler: Arc<ActiveHandlerObject>,
+    state: Arc<Mutex<ActiveHandlerState>>,
+    bot_registry: Option<BotRegistry>,
+) {
+    let name = active_handler.name().to_string();
+    let restart_policy = active_handler.restart_policy();
+    let mut restarts = Restarts::default();
+    loop {
+        let started = Instant::now();
+        // the JoinSet aborts the running task when this supervisor is aborted
+        let mut task = JoinSet::new();
+        let active_handler_ = active_handler.clone();
+        task.spawn(scope_registry(bot_registry.clone(), async move {
+            active_handler_.run_forever().await
+        }));
+
+        let failed = match task.join_next().await {
+            Some(Ok(Ok(()))) => {
+                tracing::info!("Active handler {} finished", name);
+                false
+            }
+            Some(Ok(Err(e))) => {
+                tracing::error!("Active handler {} error: {:?}", name, e);
+                true
+            }
+            Some(Err(e)) => {
+                tracing::error!("Active handler {} panicked: {:?}", name, e);
+                true
+            }
+            None => unreachable!("the JoinSet contains a task"),
+        };
+        let final_state = if failed {
+            ActiveHandlerState::Failed
+        } else {
+            ActiveHandlerState::Finished
+        };
+
+        let Some(backoff) = restart_policy.backoff(failed) else {
+            *lock_state(&state) = final_state;
+            return;
+        };
+        let Some((attempt, delay)) = restarts.next(backoff, started) else {
+            tracing::error!("Active handler {} stopped too many times, giving up", name);
+            *lock_state(&state) = final_state;
+            return;
+        };
+
+        *lock_state(&state) = ActiveHandlerState::Restarting { attempt };
+        tracing::info!(
+            "Restarting active handler {} in {:?} (attempt {})",
+            name,
+            delay,
+            attempt
+        );
+        tokio::time::sleep(delay).await;
+        *lock_state(&state) = ActiveHandlerState::Running;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::message;
+
+    type Log = Arc<Mutex<Vec<&'static str>>>;
+
+    /// An event handler logging its name once it ran for `delay`
+    struct Step {
+        name: &'static str,
+        priority: u8,
+        delay: Duration,
+        propagation: Propagation,
+        log: Log,
+    }
+
+    #[async_trait]
+    impl EventHandlerTrait for Step {
+        async fn handle_propagation(&self, _matcher: Matcher) -> Result<Propagation> {
+            tokio::time::sleep(self.delay).await;
+            self.log.lock().unwrap().push(self.name);
+            Ok(self.propagation)
+        }
+
+        fn get_priority(&self) -> u8 {
+            self.priority
+        }
+    }
+
+    fn pool(log: &Log, steps: &[(&'static str, u8, u64, Propagation)]) -> EventHandlerPool {
+        let mut pool = EventHandlerPool::new();
+        for &(name, priority, delay, propagation) in steps {
+            pool.add_handler(Handler::event(Step {
+                name,
+                priority,
+                delay: Duration::from_millis(delay),
+                propagation,
+                log: log.clone(),
+            }));
+        }
+        pool
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn priorities_run_in_ascending_order() {
+        let log = Log::default();
+        let pool = pool(
+            &log,
+            &[
+                ("last", 200, 0, Propagation::Continue),
+                ("slow first", 1, 30, Propagation::Continue),
+                ("fast first", 1, 0, Propagation::Continue),
+                ("second", 2, 0, Propagation::Continue),
+            ],
+        );
+        pool.handle(message(Some("g"), "1", "hi"), None).await;
+        // the handlers of the same priority run concurrently, the next priority waits for them
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["fast first", "slow first", "second", "last"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stop_skips_the_larger_priorities() {
+        let log = Log::default();
+        let pool = pool(
+            &log,
+            &[
+                ("first", 1, 0, Propagation::Continue),
+                ("consuming", 2, 0, Propagation::Stop),
+                ("same priority", 2, 10, Propagation::Continue),
+                ("skipped", 3, 0, Propagation::Continue),
+            ],
+        );
+        pool.handle(message(Some("g"), "1", "hi"), None).await;
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["first", "consuming", "same priority"]
+        );
+    }
 }
@@ -0,0 +1,398 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use crate::{
+    command::{Command, CommandContext},
+    matcher::Matcher,
+    plugin::PluginRegistry,
+    source::{
+        message::{Message, MessageSegment},
+        user::{Role, User},
+    },
+};
+
+/// Permission decides who may run a command
+#[derive(Clone, Debug, PartialEq, Default)]
+pub enum Permission {
+    #[default]
+    Everyone,
+    /// The owner and the admins of the group, it's never permitted in a private chat
+    GroupAdmin,
+    /// The owner of the group, it's never permitted in a private chat
+    GroupOwner,
+    /// The users with the `(server, id)` pairs, e.g. the superusers of the bot
+    Users(Vec<(String, String)>),
+    /// Permitted only if all the permissions are, e.g. a subcommand is only permitted if its parent is
+    All(Vec<Permission>),
+}
+
+impl Permission {
+    /// Whether the user who triggered the event is permitted
+    pub fn allows(&self, matcher: &Matcher) -> bool {
+        let user = matcher.try_get_user();
+        let role = || {
+            matcher.try_get_group()?;
+            user?.group_info.as_ref()?.role.clone()
+        };
+        match self {
+            Permission::Everyone => true,
+            Permission::GroupAdmin => matches!(role(), Some(Role::Owner | Role::Admin)),
+            Permission::GroupOwner => matches!(role(), Some(Role::Owner)),
+            Permission::Users(users) => user.is_some_and(|user| {
+                let server = matcher.bot.server();
+                users
+                    .iter()
+                    .any(|(user_server, id)| user_server == server && *id == user.id)
+            }),
+            Permission::All(permissions) => permissions
+                .iter()
+                .all(|permission| permission.allows(matcher)),
+        }
+    }
+
+    /// The permission allowing only who both permissions allow
+    pub fn and(&self, other: &Permission) -> Permission {
+        let parts = |permission: &Permission| match permission {
+            Permission::Everyone => Vec::new(),
+            Permission::All(permissions) => permissions.clone(),
+            permission => vec![permission.clone()],
+        };
+        let mut permissions = parts(self);
+        permissions.extend(parts(other));
+        match permissions.len() {
+            0 => Permission::Everyone,
+            1 => permissions.remove(0),
+            _ => Permission::All(permissions),
+        }
+    }
+}
+
+/// HandlerMetadata describes a command or a feature of an event handler, it's listed by the help command
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct HandlerMetadata {
+    pub name: String,
+    pub description: Option<String>,
+    pub usage: Option<String>,
+    pub examples: Vec<String>,
+    pub permission: Permission,
+    /// The name of the Plugin of the event handler, the entry is left out where the plugin is disabled
+    pub plugin: Option<String>,
+}
+
+impl HandlerMetadata {
+    pub fn new<T: Into<String>>(name: T) -> Self {
+        HandlerMetadata {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn description<T: Into<String>>(mut self, description: T) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    pub fn usage<T: Into<String>>(mut self, usage: T) -> Self {
+        self.usage = Some(usage.into());
+        self
+    }
+
+    pub fn example<T: Into<String>>(mut self, example: T) -> Self {
+        self.examples.push(example.into());
+        self
+    }
+
+    pub fn permission(mut self, permission: Permission) -> Self {
+        self.permission = permission;
+        self
+    }
+
+    fn summary(&self) -> String {
+        match &self.description {
+            Some(description) => format!("{} - {}", self.name, description),
+            None => self.name.clone(),
+        }
+    }
+
+    fn details(&self) -> String {
+        let mut text = self.summary();
+        if let Some(usage) = &self.usage {
+            text.push_str(&format!("\nUsage: {}", usage));
+        }
+        if !self.examples.is_empty() {
+            text.push_str("\nExamples:");
+            for example in &self.examples {
+                text.push_str(&format!("\n  {}", example));
+            }
+        }
+        text
+    }
+}
+
+/// HelpMenu keeps the HandlerMetadata of the event handlers of an OxideBotManager.
+/// It's a costless cloneable handle, the event handlers added later are listed too.
+#[derive(Clone, Default)]
+pub struct HelpMenu {
+    entries: Arc<Mutex<Vec<HandlerMetadata>>>,
+    plugins: PluginRegistry,
+}
+
+impl HelpMenu {
+    pub(crate) fn extend(&self, metadata: Vec<HandlerMetadata>) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .extend(metadata);
+    }
+
+    /// List the HandlerMetadata of all event handlers
+    pub fn list(&self) -> Vec<HandlerMetadata> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Get the registry deciding where the entries of the plugins are listed
+    pub(crate) fn plugin_registry(&self) -> PluginRegistry {
+        self.plugins.clone()
+    }
+
+    /// List the HandlerMetadata that the user who triggered the event is permitted to run,
+    /// leaving out those of the plugins disabled for the event
+    pub async fn permitted(&self, matcher: &Matcher) -> Vec<HandlerMetadata> {
+        let entries = self
+            .entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|metadata| metadata.permission.allows(matcher))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut permitted = Vec::with_capacity(entries.len());
+        for metadata in entries {
+            let enabled = match &metadata.plugin {
+                Some(plugin) => self.plugins.is_enabled(plugin, matcher).await,
+                None => true,
+            };
+            if enabled {
+                permitted.push(metadata);
+            }
+        }
+        permitted
+    }
+}
+
+/// HelpConfig decides how the help command renders the HelpMenu
+#[derive(Clone, Debug, PartialEq)]
+pub struct HelpConfig {
+    /// The number of entries on a page
+    pub page_size: usize,
+    /// A page with more entries than this is sent as a bundle of forwarded messages instead of a text
+    pub forward_threshold: Option<usize>,
+}
+
+impl Default for HelpConfig {
+    fn default() -> Self {
+        HelpConfig {
+            page_size: 10,
+            forward_threshold: None,
+        }
+    }
+}
+
+/// Create the `help [page | name]` command listing the entries of the HelpMenu that the user is permitted to run,
+/// or the usage and examples of one of them, named with its subcommands, e.g. `help admin ban`.
+pub fn help_command(menu: HelpMenu, config: HelpConfig) -> Command {
+    Command::new("help")
+        .description("List the commands")
+        .usage("[page | name]")
+        .handler(move |ctx: CommandContext| {
+            let menu = menu.clone();
+            let config = config.clone();
+            async move { reply_help(ctx, menu, config).await }
+        })
+}
+
+/// The name without the prefix of its command, e.g. `admin ban` for `/admin ban`,
+/// so the commands are found whatever prefix the help command was invoked with, even a mention
+fn unprefixed(name: &str) -> &str {
+    name.trim_start_matches(|c: char| !c.is_alphanumeric())
+}
+
+async fn reply_help(ctx: CommandContext, menu: HelpMenu, config: HelpConfig) -> Result<()> {
+    let entries = menu.permitted(&ctx.matcher).await;
+    let args = ctx.text_args();
+    let page = match args.as_slice() {
+        [] => Some(1),
+        [arg] => arg.parse::<usize>().ok(),
+        _ => None,
+    };
+    let Some(page) = page else {
+        // the entries are named with their subcommands, e.g. `/admin ban`
+        let name = args.join(" ");
+        let entry = entries
+            .iter()
+            .find(|entry| unprefixed(&entry.name) == unprefixed(&name));
+        return match entry {
+            Some(entry) => ctx.reply(entry.details()).await,
+            None => ctx.reply(format!("No such command: {}", name)).await,
+        };
+    };
+
+    let page_size = config.page_size.max(1);
+    let pages = entries.len().div_ceil(page_size).max(1);
+    let page = page.clamp(1, pages);
+    let entries = entries
+        .iter()
+        .skip((page - 1) * page_size)
+        .take(page_size)
+        .collect::<Vec<_>>();
+    let title = format!("Commands (page {}/{})", page, pages);
+    let footer = (page < pages).then(|| {
+        format!(
+            "Send {}{} {} for the next page",
+            ctx.prefix,
+            ctx.command.join(" "),
+            page + 1
+        )
+    });
+
+    if config
+        .forward_threshold
+        .is_some_and(|threshold| entries.len() > threshold)
+    {
+        let bot_id = ctx.matcher.bot_id().await;
+        let node = |text: String| MessageSegment::ForwardCustomNode {
+            user: Some(User {
+                id: bot_id.clone().unwrap_or_default(),
+                ..Default::default()
+            }),
+            message: Message {
+                id: String::new(),
+                segments: vec![MessageSegment::text(text)],
+            },
+        };
+        let nodes = std::iter::once(title)
+            .chain(entries.iter().map(|entry| entry.details()))
+            .chain(footer)
+            .map(node)
+            .collect();
+        ctx.matcher.try_send_message(nodes).await?;
+        return Ok(());
+    }
+
+    let mut text = title;
+    if entries.is_empty() {
+        text.push_str("\nNo commands");
+    }
+    for entry in entries {
+        text.push_str(&format!("\n{}", entry.summary()));
+    }
+    if let Some(footer) = footer {
+        text.push_str(&format!("\n{}", footer));
+    }
+    ctx.reply(text).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::{CommandPrefix, CommandRouter},
+        handler::EventHandlerTrait,
+        testing::{message_to, TestBot},
+    };
+
+    fn router(prefix: CommandPrefix, config: HelpConfig) -> CommandRouter {
+        let menu = HelpMenu::default();
+        menu.extend(
+            (1..=5)
+                .map(|i| HandlerMetadata::new(format!("/command{}", i)).description("Does things"))
+                .chain([HandlerMetadata::new("/admin ban").usage("/admin ban <user>")])
+                .collect(),
+        );
+        CommandRouter::new()
+            .prefixes(vec![prefix])
+            .command(help_command(menu, config))
+    }
+
+    /// Send the message to the router and get the text of the reply
+    async fn reply(router: &CommandRouter, segments: Vec<MessageSegment>) -> Vec<MessageSegment> {
+        let bot = TestBot::new("bot");
+        let mut matcher = message_to(&bot, Some("g"), "1", "");
+        matcher.try_get_message_mut().unwrap().segments = segments;
+        router.handle_propagation(matcher).await.unwrap();
+        let mut sent = bot.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        sent.remove(0).1
+    }
+
+    fn text(text: &str) -> Vec<MessageSegment> {
+        vec![MessageSegment::text(text)]
+    }
+
+    #[tokio::test]
+    async fn the_entries_are_paginated() {
+        let config = HelpConfig {
+            page_size: 4,
+            ..Default::default()
+        };
+        let commands = router(CommandPrefix::text("/"), config);
+        assert_eq!(
+            reply(&commands, text("/help")).await,
+            text(
+                "Commands (page 1/2)\n/command1 - Does things\n/command2 - Does things\n\
+                 /command3 - Does things\n/command4 - Does things\nSend /help 2 for the next page"
+            )
+        );
+        // the pages out of range are clamped
+        assert_eq!(
+            reply(&commands, text("/help 9")).await,
+            text("Commands (page 2/2)\n/command5 - Does things\n/admin ban")
+        );
+    }
+
+    #[tokio::test]
+    async fn large_pages_are_forwarded() {
+        let config = HelpConfig {
+            page_size: 10,
+            forward_threshold: Some(3),
+        };
+        let commands = router(CommandPrefix::text("/"), config);
+        let nodes = reply(&commands, text("/help")).await;
+        assert_eq!(nodes.len(), 7);
+        match &nodes[6] {
+            MessageSegment::ForwardCustomNode { user, message } => {
+                assert_eq!(user.as_ref().unwrap().id, "bot");
+                assert_eq!(
+                    message.segments,
+                    text("/admin ban\nUsage: /admin ban <user>")
+                );
+            }
+            node => panic!("unexpected segment {:?}", node),
+        }
+    }
+
+    #[tokio::test]
+    async fn entries_are_found_by_name_whatever_the_prefix() {
+        let details = text("/admin ban\nUsage: /admin ban <user>");
+        let commands = router(CommandPrefix::text("/"), HelpConfig::default());
+        assert_eq!(reply(&commands, text("/help admin ban")).await, details);
+        assert_eq!(reply(&commands, text("/help /admin ban")).await, details);
+        assert_eq!(
+            reply(&commands, text("/help admin kick")).await,
+            text("No such command: admin kick")
+        );
+
+        let commands = router(CommandPrefix::Mention, HelpConfig::default());
+        let mention = vec![
+            MessageSegment::At {
+                user_id: "bot".to_string(),
+            },
+            MessageSegment::text("help admin ban"),
+        ];
+        assert_eq!(reply(&commands, mention).await, details);
+    }
+}
@@ -0,0 +1,508 @@
+use std::{
+    any::Any,
+    fmt,
+    future::Future,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{
+    api::{
+        payload::{GroupAdminChangeType, GroupMuteType, RequestResponse, SendMessageTarget},
+        BotGetFriendListResponse, BotGetGroupListResponse, BotGetProfileResponse, CallApiTrait,
+        GetMessageDetailResponse, GroupGetFileCountResponse, GroupGetFsListResponse,
+        GroupGetProfileResponse, GroupMemberListResponse, SendMessageResponse,
+        UserGetProfileResponse,
+    },
+    bot::{BotObject, BotTrait},
+    channel::EventSender,
+    source::{
+        bot::BotInfo,
+        group::GroupProfile,
+        message::{File, MessageSegment},
+        user::UserProfile,
+    },
+};
+
+/// Destination is where an Outgoing message goes
+#[derive(Clone, Debug, PartialEq)]
+pub enum Destination {
+    /// Sent with `send_message`
+    Send(SendMessageTarget),
+    /// Replacing the message with the id with `edit_messagee`
+    Edit(String),
+}
+
+/// Outgoing is a message that a bot is about to send or edit
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outgoing {
+    pub message: Vec<MessageSegment>,
+    pub destination: Destination,
+}
+
+/// Interceptor sees every message sent or edited by the bots of an OxideBotManager, e.g. to censor words,
+/// append a signature, enforce a maximum length or block the sends to muted groups.
+/// It returns the messages to send instead: the same one modified, several ones when split, or none to drop it.
+/// An error rejects the message, it's returned by `send_message` or `edit_messagee`.
+/// When several messages are returned and one of them fails to be sent, the error is a PartialDelivery.
+#[async_trait]
+pub trait InterceptorTrait: Send + Sync {
+    async fn intercept(&self, outgoing: Outgoing, bot: BotObject) -> Result<Vec<Outgoing>>;
+}
+
+pub type InterceptorObject = Box<dyn InterceptorTrait>;
+
+/// Any `Fn(Outgoing, BotObject) -> impl Future<Output = Result<Vec<Outgoing>>>` closure is an Interceptor
+#[async_trait]
+impl<F, Fut> InterceptorTrait for F
+where
+    F: Fn(Outgoing, BotObject) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Vec<Outgoing>>> + Send + 'static,
+{
+    async fn intercept(&self, outgoing: Outgoing, bot: BotObject) -> Result<Vec<Outgoing>> {
+        self(outgoing, bot).await
+    }
+}
+
+impl<I: InterceptorTrait + 'static> From<I> for InterceptorObject {
+    fn from(interceptor: I) -> Self {
+        Box::new(interceptor)
+    }
+}
+
+/// Interceptors is the chain of Interceptors of an OxideBotManager, run in order of addition.
+/// It's a costless cloneable handle, the Interceptors added later apply to the events received after.
+#[derive(Clone, Default)]
+pub struct Interceptors {
+    chain: Arc<RwLock<Vec<Arc<InterceptorObject>>>>,
+}
+
+impl Interceptors {
+    pub fn add(&self, interceptor: InterceptorObject) {
+        self.chain
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Arc::new(interceptor));
+    }
+
+    fn chain(&self) -> Vec<Arc<InterceptorObject>> {
+        self.chain.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Wrap the bot so its sent and edited messages pass through the Interceptors, it's unchanged if there is none.
+    /// A bot that is already wrapped, e.g. the bot of a Matcher, is unwrapped first, so the Interceptors never run twice.
+    pub fn wrap(&self, bot: BotObject) -> BotObject {
+        let bot = bot.unintercepted().unwrap_or(bot);
+        if self
+            .chain
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty()
+        {
+            return bot;
+        }
+        Box::new(InterceptedBot {
+            inner: bot,
+            interceptors: self.clone(),
+        })
+    }
+
+    /// Run the outgoing message through the Interceptors
+    async fn run(&self, outgoing: Outgoing, bot: &BotObject) -> Result<Vec<Outgoing>> {
+        let mut messages = vec![outgoing];
+        for interceptor in self.chain() {
+            let mut intercepted = Vec::new();
+            for outgoing in messages {
+                intercepted.extend(interceptor.intercept(outgoing, bot.clone()).await?);
+            }
+            messages = intercepted;
+        }
+        Ok(messages)
+    }
+}
+
+impl std::fmt::Debug for Interceptors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let len = self.chain.read().unwrap_or_else(|e| e.into_inner()).len();
+        f.debug_struct("Interceptors").field("len", &len).finish()
+    }
+}
+
+/// PartialDelivery is the error of `send_message` or `edit_messagee` when the Interceptors turned a message into several ones,
+/// and one of them failed after the ones before it were sent.
+/// Get it with `error.downcast_ref::<PartialDelivery>()` to know what was sent.
+#[derive(Debug)]
+pub struct PartialDelivery {
+    /// The number of messages sent or edited before the failure
+    pub delivered: usize,
+    /// The responses of the messages sent before the failure
+    pub responses: Vec<SendMessageResponse>,
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for PartialDelivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed after delivering {} messages: {:?}",
+            self.delivered, self.error
+        )
+    }
+}
+
+impl std::error::Error for PartialDelivery {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+/// InterceptedBot is a bot whose sent and edited messages pass through the Interceptors,
+/// the other calls go to the bot unchanged. `as_any` still downcasts to the wrapped bot.
+#[derive(Clone)]
+struct InterceptedBot {
+    inner: BotObject,
+    interceptors: Interceptors,
+}
+
+impl InterceptedBot {
+    /// Send or edit the intercepted messages in order, stopping at the first failure
+    async fn deliver(&self, outgoing: Outgoing) -> Result<Vec<SendMessageResponse>> {
+        let mut responses = Vec::new();
+        for (delivered, outgoing) in self
+            .interceptors
+            .run(outgoing, &self.inner)
+            .await?
+            .into_iter()
+            .enumerate()
+        {
+            let result = match outgoing.destination {
+                Destination::Send(target) => self
+                    .inner
+                    .send_message(outgoing.message, target)
+                    .await
+                    .map(|sent| responses.extend(sent)),
+                Destination::Edit(message_id) => {
+                    self.inner.edit_messagee(message_id, outgoing.message).await
+                }
+            };
+            match result {
+                Ok(()) => {}
+                Err(error) if delivered == 0 => return Err(error),
+                Err(error) => {
+                    return Err(PartialDelivery {
+                        delivered,
+                        responses,
+                        error,
+                    }
+                    .into())
+                }
+            }
+        }
+        Ok(responses)
+    }
+}
+
+#[async_trait]
+impl BotTrait for InterceptedBot {
+    async fn bot_info(&self) -> BotInfo {
+        self.inner.bot_info().await
+    }
+
+    async fn start_sending_events(&self, sender: EventSender) {
+        self.inner.start_sending_events(sender).await
+    }
+
+    fn server(&self) -> &'static str {
+        self.inner.server()
+    }
+
+    fn clone_box(&self) -> BotObject {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self.inner.as_any()
+    }
+
+    fn unintercepted(&self) -> Option<BotObject> {
+        Some(self.inner.clone())
+    }
+}
+
+#[async_trait]
+impl CallApiTrait for InterceptedBot {
+    async fn send_message(
+        &self,
+        message: Vec<MessageSegment>,
+        target: SendMessageTarget,
+    ) -> Result<Vec<SendMessageResponse>> {
+        self.deliver(Outgoing {
+            message,
+            destination: Destination::Send(target),
+        })
+        .await
+    }
+
+    async fn edit_messagee(
+        &self,
+        message_id: String,
+        new_message: Vec<MessageSegment>,
+    ) -> Result<()> {
+        self.deliver(Outgoing {
+            message: new_message,
+            destination: Destination::Edit(message_id),
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_message(&self, message_id: String) -> Result<()> {
+        self.inner.delete_message(message_id).await
+    }
+
+    async fn get_message_detail(&self, message_id: String) -> Result<GetMessageDetailResponse> {
+        self.inner.get_message_detail(message_id).await
+    }
+
+    async fn set_message_reaction(&self, message_id: String, reaction_id: String) -> Result<()> {
+        self.inner
+            .set_message_reaction(message_id, reaction_id)
+            .await
+    }
+
+    async fn get_group_member_list(&self, group_id: String) -> Result<GroupMemberListResponse> {
+        self.inner.get_group_member_list(group_id).await
+    }
+
+    async fn kick_group_member(
+        &self,
+        group_id: String,
+        user_id: String,
+        reject_add_request: Option<bool>,
+    ) -> Result<()> {
+        self.inner
+            .kick_group_member(group_id, user_id, reject_add_request)
+            .await
+    }
+
+    async fn mute_group(
+        &self,
+        group_id: String,
+        duration: Option<Duration>,
+        r#type: GroupMuteType,
+    ) -> Result<()> {
+        self.inner.mute_group(group_id, duration, r#type).await
+    }
+
+    async fn mute_group_member(
+        &self,
+        group_id: String,
+        user_id: String,
+        r#type: GroupMuteType,
+        duration: Option<Duration>,
+    ) -> Result<()> {
+        self.inner
+            .mute_group_member(group_id, user_id, r#type, duration)
+            .await
+    }
+
+    async fn change_group_admin(
+        &self,
+        group_id: String,
+        user_id: String,
+        r#type: GroupAdminChangeType,
+    ) -> Result<()> {
+        self.inner
+            .change_group_admin(group_id, user_id, r#type)
+            .await
+    }
+
+    async fn set_group_member_alias(
+        &self,
+        group_id: String,
+        user_id: String,
+        new_alias: String,
+    ) -> Result<()> {
+        self.inner
+            .set_group_member_alias(group_id, user_id, new_alias)
+            .await
+    }
+
+    async fn get_group_profile(&self, group_id: String) -> Result<GroupGetProfileResponse> {
+        self.inner.get_group_profile(group_id).await
+    }
+
+    async fn set_group_profile(&self, group_id: String, new_profile: GroupProfile) -> Result<()> {
+        self.inner.set_group_profile(group_id, new_profile).await
+    }
+
+    async fn get_group_file_count(
+        &self,
+        group_id: String,
+        parent_folder_id: Option<String>,
+    ) -> Result<GroupGetFileCountResponse> {
+        self.inner
+            .get_group_file_count(group_id, parent_folder_id)
+            .await
+    }
+
+    async fn get_group_fs_list(
+        &self,
+        group_id: String,
+        start_index: u64,
+        count: u64,
+    ) -> Result<GroupGetFsListResponse> {
+        self.inner
+            .get_group_fs_list(group_id, start_index, count)
+            .await
+    }
+
+    async fn delete_group_file(&self, group_id: String, file_id: String) -> Result<()> {
+        self.inner.delete_group_file(group_id, file_id).await
+    }
+
+    async fn delete_group_folder(&self, group_id: String, folder_id: String) -> Result<()> {
+        self.inner.delete_group_folder(group_id, folder_id).await
+    }
+
+    async fn create_group_folder(
+        &self,
+        group_id: String,
+        folder_name: String,
+        parent_folder_id: Option<String>,
+    ) -> Result<()> {
+        self.inner
+            .create_group_folder(group_id, folder_name, parent_folder_id)
+            .await
+    }
+
+    async fn get_user_profile(&self, user_id: String) -> Result<UserGetProfileResponse> {
+        self.inner.get_user_profile(user_id).await
+    }
+
+    async fn set_bot_profile(&self, new_profile: UserProfile) -> Result<()> {
+        self.inner.set_bot_profile(new_profile).await
+    }
+
+    async fn get_bot_profile(&self) -> Result<BotGetProfileResponse> {
+        self.inner.get_bot_profile().await
+    }
+
+    async fn get_bot_friend_list(&self) -> Result<BotGetFriendListResponse> {
+        self.inner.get_bot_friend_list().await
+    }
+
+    async fn get_bot_group_list(&self) -> Result<BotGetGroupListResponse> {
+        self.inner.get_bot_group_list().await
+    }
+
+    async fn handle_add_friend_request(&self, id: String, response: RequestResponse) -> Result<()> {
+        self.inner.handle_add_friend_request(id, response).await
+    }
+
+    async fn handle_add_group_request(&self, id: String, response: RequestResponse) -> Result<()> {
+        self.inner.handle_add_group_request(id, response).await
+    }
+
+    async fn handle_invite_group_request(
+        &self,
+        id: String,
+        response: RequestResponse,
+    ) -> Result<()> {
+        self.inner.handle_invite_group_request(id, response).await
+    }
+
+    async fn get_file_info(&self, file_id: String) -> Result<File> {
+        self.inner.get_file_info(file_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestBot;
+
+    /// Interceptors splitting every message on its lines
+    fn split_lines() -> Interceptors {
+        let interceptors = Interceptors::default();
+        interceptors.add(
+            (|outgoing: Outgoing, _bot: BotObject| async move {
+                let text = outgoing
+                    .message
+                    .iter()
+                    .filter_map(|segment| match segment {
+                        MessageSegment::Text { content } => Some(content.clone()),
+                        _ => None,
+                    })
+                    .collect::<String>();
+                Ok(text
+                    .lines()
+                    .map(|line| Outgoing {
+                        message: vec![MessageSegment::text(line)],
+                        destination: outgoing.destination.clone(),
+                    })
+                    .collect())
+            })
+            .into(),
+        );
+        interceptors
+    }
+
+    #[tokio::test]
+    async fn wrapped_bots_are_intercepted_once() {
+        let bot = TestBot::new("bot");
+        let interceptors = Interceptors::default();
+        interceptors.add(
+            (|mut outgoing: Outgoing, _bot: BotObject| async move {
+                outgoing.message.push(MessageSegment::text("!"));
+                Ok(vec![outgoing])
+            })
+            .into(),
+        );
+        let wrapped = interceptors.wrap(interceptors.wrap(Box::new(bot.clone())));
+        wrapped
+            .send_message(
+                vec![MessageSegment::text("hi")],
+                SendMessageTarget::Group("g".to_string()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            bot.sent.lock().unwrap()[0].1,
+            [MessageSegment::text("hi"), MessageSegment::text("!")]
+        );
+    }
+
+    #[tokio::test]
+    async fn partial_deliveries_keep_the_responses() {
+        let bot = TestBot {
+            failing: Some("two".to_string()),
+            ..TestBot::new("bot")
+        };
+        let wrapped = split_lines().wrap(Box::new(bot.clone()));
+        let target = SendMessageTarget::Group("g".to_string());
+
+        let error = wrapped
+            .send_message(
+                vec![MessageSegment::text("one\ntwo\nthree")],
+                target.clone(),
+            )
+            .await
+            .unwrap_err();
+        let partial = error.downcast_ref::<PartialDelivery>().unwrap();
+        assert_eq!(partial.delivered, 1);
+        assert_eq!(partial.responses.len(), 1);
+        assert_eq!(bot.sent.lock().unwrap().len(), 1);
+
+        // nothing was sent, the error is returned as it is
+        let error = wrapped
+            .send_message(vec![MessageSegment::text("two\nthree")], target)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<PartialDelivery>().is_none());
+        assert_eq!(bot.sent.lock().unwrap().len(), 1);
+    }
+}
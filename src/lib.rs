@@ -1,23 +1,48 @@
-#![doc = include_str!("/mnt/disk/git/oxidebot/Readme.md")]
+#![doc = include_str!("../Readme.md")]
 
+pub mod access;
 pub mod api;
 pub mod bot;
+pub mod channel;
+pub mod command;
+pub mod concurrency;
+pub mod dedup;
+pub mod dispatch;
+pub mod error;
 pub mod event;
+pub mod extensions;
+pub mod extract;
 pub mod filter;
 pub mod handler;
+pub mod help;
+pub mod intercept;
 pub mod manager;
 pub mod matcher;
+pub mod plugin;
+pub mod rate_limit;
+pub mod report;
 pub mod source;
+pub mod supervisor;
+#[cfg(test)]
+mod testing;
 pub mod utils;
 
 pub use api::CallApiTrait;
-pub use bot::{get_bot, BotTrait};
+pub use bot::{get_bot, BotRegistry, BotState, BotTrait};
+pub use channel::{ChannelConfig, ChannelMode, EventSender};
+pub use error::ErrorHookTrait;
 pub use event::EventTrait;
+pub use extract::FromMatcher;
 pub use filter::FilterTrait;
+pub use filter::MiddlewareTrait;
 pub use handler::ActiveHandlerTrait;
 pub use handler::EventHandlerTrait;
 pub use handler::Handler;
+pub use handler::Propagation;
+pub use handler::SimpleEventHandlerTrait;
+pub use intercept::InterceptorTrait;
 pub use manager::OxideBotManager;
+pub use report::AfterHookTrait;
 
 pub use utils::wait::{
     wait, wait_text_generic, wait_user, wait_user_message, wait_user_text_generic, EasyBool,
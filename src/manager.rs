@@ -1,13 +1,24 @@
-use std::{future::Future, pin::Pin};
+use std::{future::Future, path::PathBuf, pin::Pin, sync::Arc, time::Duration};
 
 use crate::{
-    bot::{add_bots, BotObject},
-    filter::{FilterObject, FilterPool},
-    handler::{EventHandlerPool, Handler},
+    bot::{BotObject, BotRegistry},
+    channel::{event_channel, ChannelConfig, ChannelStats, EventReceiver, Received},
+    dispatch::{Dispatcher, InFlight, OrderingMode},
+    error::ErrorHookObject,
+    event::{meta::MetaEventObject, MetaEvent},
+    extensions::Extensions,
+    filter::{FilterConfig, FilterObject, FilterPool, MiddlewareObject},
+    handler::{ActiveHandlerRegistry, EventHandlerPool, Handler},
+    help::HelpMenu,
+    intercept::{InterceptorObject, Interceptors},
     matcher::Matcher,
+    plugin::{Plugin, PluginRegistry},
+    report::AfterHookObject,
+    supervisor::BackoffPolicy,
 };
-use tokio::sync::broadcast;
+use tokio::{sync::broadcast, task::JoinSet};
 
+#[derive(Clone)]
 pub struct BroadcastSender(broadcast::Sender<Matcher>);
 
 impl BroadcastSender {
@@ -15,16 +26,8 @@ impl BroadcastSender {
         BroadcastSender(sender)
     }
 
-    pub(crate) fn clone_sender(&self) -> broadcast::Sender<Matcher> {
-        self.0.clone()
-    }
-
     // handler maker can only use methods below
 
-    pub fn clone(&self) -> Self {
-        BroadcastSender(self.0.clone())
-    }
-
     pub fn subscribe(&self) -> broadcast::Receiver<Matcher> {
         self.0.subscribe()
     }
@@ -36,18 +39,51 @@ pub struct OxideBotManager {
     handler_pool: EventHandlerPool,
     filter_pool: FilterPool,
     broadcast_sender: BroadcastSender,
-    broadcast_receiver: broadcast::Receiver<Matcher>,
+    event_receiver: EventReceiver,
+    channel_config: ChannelConfig,
+    channel_stats: ChannelStats,
+    bot_registry: BotRegistry,
+    plugin_registry: PluginRegistry,
+    extensions: Extensions,
+    interceptors: Interceptors,
+    ordering: OrderingMode,
+    ignore_own_messages: bool,
+}
+
+impl Default for OxideBotManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl OxideBotManager {
     /// Create a new OxideBotManager
     pub fn new() -> Self {
-        let (broadcast_sender, broadcast_receiver) = broadcast::channel(100);
+        Self::with_channel(ChannelConfig::default())
+    }
+    /// Create a new OxideBotManager with the given event channel config
+    pub fn with_channel(channel_config: ChannelConfig) -> Self {
+        let extensions = Extensions::default();
+        let interceptors = Interceptors::default();
+        let (event_sender, event_receiver, broadcast_sender) =
+            event_channel(&channel_config, extensions.clone(), interceptors.clone());
+        let bot_registry = BotRegistry::new(event_sender);
+        let handler_pool = EventHandlerPool::with_bot_registry(bot_registry.clone());
+        // the help menu leaves out the commands of the plugins where they're disabled
+        let plugin_registry = handler_pool.help_menu().plugin_registry();
         OxideBotManager {
-            handler_pool: EventHandlerPool::new(),
+            handler_pool,
             filter_pool: FilterPool::new(),
             broadcast_sender: BroadcastSender::new(broadcast_sender),
-            broadcast_receiver,
+            event_receiver,
+            channel_config,
+            channel_stats: ChannelStats::default(),
+            bot_registry,
+            plugin_registry,
+            extensions,
+            interceptors,
+            ordering: OrderingMode::default(),
+            ignore_own_messages: true,
         }
     }
     /// Build a OxideBotManager with bots, handlers and filters
@@ -56,20 +92,90 @@ impl OxideBotManager {
         handlers: Vec<Handler>,
         filters: Vec<FilterObject>,
     ) -> Self {
-        let (broadcast_sender, broadcast_receiver) = broadcast::channel(100);
-        add_bots(bots, broadcast_sender.clone()).await;
-        OxideBotManager {
-            handler_pool: EventHandlerPool::build(handlers),
-            filter_pool: FilterPool::build(filters),
-            broadcast_sender: BroadcastSender::new(broadcast_sender),
-            broadcast_receiver,
+        let mut manager = Self::new();
+        for bot in bots {
+            manager = manager.bot(bot).await;
+        }
+        for handler in handlers {
+            manager = manager.handler(handler);
+        }
+        for filter in filters {
+            manager = manager.filter(filter);
         }
+        manager
     }
     /// Add a bot to the OxideBotManager
     pub async fn bot(self, bot: BotObject) -> Self {
-        add_bots(vec![bot.into()], self.broadcast_sender.clone_sender()).await;
+        self.bot_registry.add_bot(bot).await;
+        self
+    }
+    /// Set the BackoffPolicy used to restart the event task of the bots added after this call
+    pub fn bot_backoff(self, backoff: BackoffPolicy) -> Self {
+        self.bot_registry.set_backoff_policy(backoff);
         self
     }
+    /// Get the registry of the bots added to this OxideBotManager.
+    /// It's a costless cloneable handle, so you can pass it to your handlers to look up, add or remove bots at runtime.
+    pub fn bot_registry(&self) -> BotRegistry {
+        self.bot_registry.clone()
+    }
+    /// Get the statistics of the event channel, e.g. how many events were dropped
+    pub fn channel_stats(&self) -> ChannelStats {
+        self.channel_stats.clone()
+    }
+    /// Get the registry of the active handlers added to this OxideBotManager.
+    /// It's a costless cloneable handle, so you can query and stop the active handlers by name at any time.
+    pub fn active_handlers(&self) -> ActiveHandlerRegistry {
+        self.handler_pool.active_handlers()
+    }
+    /// Get the HelpMenu listing the commands of the event handlers added to this OxideBotManager.
+    /// It's a costless cloneable handle, pass it to `help::help_command` to let the users discover the commands.
+    pub fn help_menu(&self) -> HelpMenu {
+        self.handler_pool.help_menu()
+    }
+    /// Add a plugin to the OxideBotManager, its handlers and filters are skipped where it's disabled
+    pub fn plugin(mut self, plugin: Plugin) -> Self {
+        let (handlers, filters) = plugin.into_parts(&self.plugin_registry);
+        for handler in handlers {
+            self = self.handler(handler);
+        }
+        for filter in filters {
+            self = self.filter(filter);
+        }
+        self
+    }
+    /// Persist where the plugins are disabled to the JSON file at `path`, and load it if it already exists
+    pub async fn plugin_storage<P: Into<PathBuf>>(self, path: P) -> Self {
+        self.plugin_registry.set_storage(path.into()).await;
+        self
+    }
+    /// Get the registry of the plugins added to this OxideBotManager.
+    /// It's a costless cloneable handle, so you can enable and disable the plugins at runtime, or pass it to `plugin::plugin_command`.
+    pub fn plugin_registry(&self) -> PluginRegistry {
+        self.plugin_registry.clone()
+    }
+    /// Share the value with all handlers and filters, they get it by its type with `Matcher::extension` or the `Ext<T>` extractor.
+    /// A value of the same type inserted before is replaced.
+    pub fn extension<T: Send + Sync + 'static>(self, value: T) -> Self {
+        self.extensions.insert(value);
+        self
+    }
+    /// Get the Extensions shared with the handlers and filters.
+    /// It's a costless cloneable handle, so you can insert or replace the values at runtime.
+    pub fn extensions(&self) -> Extensions {
+        self.extensions.clone()
+    }
+    /// Add an interceptor to the OxideBotManager, every message sent or edited by its bots passes through the interceptors in order of addition,
+    /// through the Matchers as well as through the bots got from the `BotRegistry`.
+    pub fn interceptor<I: Into<InterceptorObject>>(self, interceptor: I) -> Self {
+        self.interceptors.add(interceptor.into());
+        self
+    }
+    /// Get the Interceptors of the bots.
+    /// It's a costless cloneable handle, use `Interceptors::wrap` for the bots created outside of the OxideBotManager.
+    pub fn interceptors(&self) -> Interceptors {
+        self.interceptors.clone()
+    }
     /// Add a handler to the OxideBotManager
     pub fn handler<H: Into<Handler>>(mut self, handler: H) -> Self {
         self.handler_pool.add_handler(handler.into());
@@ -83,19 +189,250 @@ impl OxideBotManager {
         let handler = handler_creator(self.broadcast_sender.clone()).await;
         self.handler(handler)
     }
+    /// Add an error hook to the OxideBotManager, it's called when an event handler returns an error, panics or times out.
+    /// Use `ReplyOnError` to tell the user that their message failed, with an id matching the log entry.
+    pub fn error_hook<E: Into<ErrorHookObject>>(mut self, error_hook: E) -> Self {
+        self.handler_pool.add_error_hook(error_hook.into());
+        self
+    }
+    /// Add an after hook to the OxideBotManager, it's called with the DispatchReport of the event handlers that ran,
+    /// once they all finished with an event. Use `FallbackReply` to answer the messages that no event handler consumed.
+    pub fn after_hook<A: Into<AfterHookObject>>(mut self, after_hook: A) -> Self {
+        self.handler_pool.add_after_hook(after_hook.into());
+        self
+    }
+    /// Set the FilterConfig deciding the timeouts of the filters and what to do when they panic or time out
+    pub fn filter_config(mut self, config: FilterConfig) -> Self {
+        self.filter_pool.set_config(config);
+        self
+    }
+    /// Set the OrderingMode deciding in which order the events of the same group or private chat are handled
+    pub fn ordering(mut self, ordering: OrderingMode) -> Self {
+        self.ordering = ordering;
+        self
+    }
+    /// Whether the messages sent by any of the bots of this OxideBotManager are dropped before the filters, true by default.
+    /// Turn it off to handle the messages echoed back by the platform yourself.
+    pub fn ignore_own_messages(mut self, ignore_own_messages: bool) -> Self {
+        self.ignore_own_messages = ignore_own_messages;
+        self
+    }
     /// Add a filter to the OxideBotManager
     pub fn filter<F: Into<FilterObject>>(mut self, filter: F) -> Self {
         self.filter_pool.add_filter(filter.into());
         self
     }
+    /// Add a middleware to the OxideBotManager, it runs among the filters in order of priority
+    /// and can enrich or rewrite the event before it reaches the handlers, or stop it with a reply.
+    pub fn middleware<M: Into<MiddlewareObject>>(mut self, middleware: M) -> Self {
+        self.filter_pool.add_middleware(middleware.into());
+        self
+    }
     /// Run the OxideBotManager, this function will block the current thread
-    pub async fn run_block(mut self) -> ! {
+    pub async fn run_block(self) -> ! {
+        self.run_until(std::future::pending::<()>(), Duration::ZERO)
+            .await;
+        unreachable!("run_until returned without a shutdown signal")
+    }
+    /// Run the OxideBotManager until `shutdown` completes, then shut down gracefully:
+    /// no more events are accepted, the bots and active handlers are aborted,
+    /// and the in-flight event handlers get `grace_period` to finish before they are aborted.
+    ///
+    /// `shutdown` can be any future, e.g. `tokio::signal::ctrl_c()` or a `oneshot::Receiver`.
+    pub async fn run_until<F: Future>(self, shutdown: F, grace_period: Duration) {
+        let OxideBotManager {
+            handler_pool,
+            filter_pool,
+            broadcast_sender,
+            mut event_receiver,
+            channel_config,
+            channel_stats,
+            bot_registry,
+            plugin_registry: _,
+            extensions,
+            interceptors: _,
+            ordering,
+            ignore_own_messages,
+        } = self;
+        let lossless = matches!(event_receiver, EventReceiver::Lossless(_));
+        // in lossless mode, the events being dispatched also count for the channel capacity,
+        // so the bots are backpressured instead of piling up dispatch tasks
+        let in_flight = lossless.then(|| InFlight::new(channel_config.capacity));
+        let dispatcher = Arc::new(Dispatcher::new(
+            handler_pool,
+            filter_pool,
+            bot_registry.clone(),
+            ordering,
+            ignore_own_messages,
+            in_flight,
+        ));
+        let mut event_tasks = JoinSet::new();
+
+        tokio::pin!(shutdown);
+        let mut pending_lag = 0;
         loop {
-            if let Ok(matcher) = self.broadcast_receiver.recv().await {
-                if self.filter_pool.filter(matcher.clone()).await {
-                    self.handler_pool.handle(matcher);
-                }
+            // reap the finished tasks so the set doesn't grow forever
+            while event_tasks.try_join_next().is_some() {}
+
+            tokio::select! {
+                _ = &mut shutdown => break,
+                received = event_receiver.recv() => match received {
+                    Received::Matcher(matcher) => {
+                        if pending_lag > 0 && channel_config.lag_event {
+                            let event_object = Box::new(MetaEventObject {
+                                server: matcher.bot.server(),
+                                event: MetaEvent::LaggedEvent {
+                                    skipped: pending_lag,
+                                },
+                            });
+                            for mut lag_matcher in Matcher::new(event_object, matcher.bot.clone()) {
+                                lag_matcher.extensions = extensions.clone();
+                                let turns = dispatcher.prepare(&lag_matcher).await;
+                                event_tasks.spawn(dispatcher.clone().dispatch(lag_matcher, turns));
+                            }
+                        }
+                        pending_lag = 0;
+
+                        if lossless {
+                            // the waiters only subscribe to the broadcast channel, it has no receiver when nobody waits
+                            let _ = broadcast_sender.0.send(matcher.clone());
+                        }
+                        let turns = tokio::select! {
+                            _ = &mut shutdown => break,
+                            turns = dispatcher.prepare(&matcher) => turns,
+                        };
+                        event_tasks.spawn(dispatcher.clone().dispatch(matcher, turns));
+                    }
+                    Received::Lagged(skipped) => {
+                        channel_stats.add_dropped_events(skipped);
+                        pending_lag += skipped;
+                        tracing::warn!(
+                            "OxideBotManager lagged behind, {} events were dropped ({} in total), consider a larger channel capacity",
+                            skipped,
+                            channel_stats.dropped_events()
+                        );
+                    }
+                    Received::Closed => {
+                        tracing::error!("Event channel closed");
+                        break;
+                    }
+                },
             }
         }
+
+        tracing::info!("Shutting down OxideBotManager");
+        bot_registry.abort_all().await;
+        dispatcher.handler_pool.stop_active_handlers();
+
+        let finished = tokio::time::timeout(grace_period, async {
+            while event_tasks.join_next().await.is_some() {}
+        })
+        .await;
+        if finished.is_err() {
+            tracing::warn!(
+                "{} events were still being handled after {:?}, aborting them",
+                event_tasks.len(),
+                grace_period
+            );
+            event_tasks.shutdown().await;
+        }
+        tracing::info!("OxideBotManager stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use tokio::{sync::oneshot, time::Instant};
+
+    use super::*;
+    use crate::testing::{message_to, wait_count, Running, TestBot};
+
+    /// A manager whose handler takes `handling` to handle each message, while counting the running handlers
+    async fn manager(
+        bot: &TestBot,
+        handling: Duration,
+        handlers: &Arc<AtomicUsize>,
+        handled: &Arc<AtomicBool>,
+    ) -> OxideBotManager {
+        let running = handlers.clone();
+        let active = Handler::active(move || {
+            let running = running.clone();
+            async move {
+                let _running = Running::new(&running);
+                std::future::pending::<anyhow::Result<()>>().await
+            }
+        });
+        let (running, handled) = (handlers.clone(), handled.clone());
+        let event = Handler::from_fn(move |matcher: Matcher| {
+            let (running, handled) = (running.clone(), handled.clone());
+            async move {
+                if matcher.try_get_message().is_some() {
+                    let _running = Running::new(&running);
+                    tokio::time::sleep(handling).await;
+                    handled.store(true, Ordering::SeqCst);
+                }
+                Ok(())
+            }
+        });
+        OxideBotManager::new()
+            .bot(Box::new(bot.clone()))
+            .await
+            .handler(active)
+            .handler(event)
+    }
+
+    /// Run the manager until a message is being handled, then shut it down and return how long it took
+    async fn shut_down_while_handling(
+        manager: OxideBotManager,
+        bot: &TestBot,
+        handlers: &Arc<AtomicUsize>,
+        grace_period: Duration,
+    ) -> Duration {
+        let (shutdown, shutdown_receiver) = oneshot::channel::<()>();
+        let running = tokio::spawn(manager.run_until(shutdown_receiver, grace_period));
+        bot.sender()
+            .await
+            .send(message_to(bot, Some("g"), "1", "x"))
+            .await
+            .unwrap();
+        // the active handler and the event handler
+        wait_count(handlers, 2).await;
+
+        let started = Instant::now();
+        shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .unwrap()
+            .unwrap();
+        started.elapsed()
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_the_handlers_in_flight() {
+        let bot = TestBot::new("bot");
+        let (handlers, handled) = (Arc::default(), Arc::default());
+        let manager = manager(&bot, Duration::from_millis(50), &handlers, &handled).await;
+
+        shut_down_while_handling(manager, &bot, &handlers, Duration::from_secs(5)).await;
+        assert!(handled.load(Ordering::SeqCst));
+        bot.wait_running(0).await;
+        wait_count(&handlers, 0).await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_aborts_the_handlers_after_the_grace_period() {
+        let bot = TestBot::new("bot");
+        let (handlers, handled) = (Arc::default(), Arc::default());
+        let manager = manager(&bot, Duration::from_secs(60), &handlers, &handled).await;
+
+        let elapsed =
+            shut_down_while_handling(manager, &bot, &handlers, Duration::from_millis(50)).await;
+        assert!(elapsed >= Duration::from_millis(50));
+        assert!(elapsed < Duration::from_secs(1));
+        assert!(!handled.load(Ordering::SeqCst));
+        bot.wait_running(0).await;
+        wait_count(&handlers, 0).await;
     }
 }
@@ -1,9 +1,13 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 use crate::{
     api::SendMessageResponse,
-    bot::BotObject,
+    bot::{BotId, BotObject},
     event::{self, Event, EventObject},
+    extensions::{EventContext, Extensions},
     source::{
         group::Group,
         message::{Message, MessageSegment},
@@ -15,11 +19,21 @@ use anyhow::Result;
 
 /// Matcher is a struct that contains the eventObject, event and the bot.
 /// It implements some methods to get the user, message, group and so on.
+/// Create it with `Matcher::new`, its extensions and context are only reachable through its methods.
 #[derive(Clone, Debug)]
 pub struct Matcher {
     pub event_object: EventObject,
     pub event: Arc<Event>,
     pub bot: BotObject,
+    /// The values shared by the OxideBotManager, set when the matcher is sent through the `EventSender`
+    pub(crate) extensions: Extensions,
+    /// The values attached to this event by the middlewares, e.g. the resolved permissions or locale of the user.
+    /// Every clone of the Matcher has its own.
+    pub(crate) context: EventContext,
+    /// Whether a message was sent with `try_send_message`, shared by the clones
+    replied: Arc<AtomicBool>,
+    /// The id of the bot cached by the BotRegistry, set when the matcher is sent by a registered bot
+    pub(crate) bot_id: Option<BotId>,
 }
 
 impl Matcher {
@@ -31,11 +45,52 @@ impl Matcher {
                 event_object: event_object.clone(),
                 event: Arc::new(event),
                 bot: bot.clone(),
+                extensions: Extensions::default(),
+                context: EventContext::default(),
+                replied: Arc::default(),
+                bot_id: None,
             });
         }
         matchers
     }
 
+    /// Get the value of the type inserted with `OxideBotManager::extension`
+    pub fn extension<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.extensions.get::<T>()
+    }
+
+    /// Get the Extensions shared by the OxideBotManager, to insert or remove values
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Get the value of the type attached to this event by a middleware
+    pub fn context<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.context.get::<T>()
+    }
+
+    /// Attach the value to this event, replacing and returning the one of the same type if any
+    pub fn insert_context<T: Send + Sync + 'static>(&mut self, value: T) -> Option<Arc<T>> {
+        self.context.insert(value)
+    }
+
+    /// Remove and return the value of the type attached to this event
+    pub fn remove_context<T: Send + Sync + 'static>(&mut self) -> Option<Arc<T>> {
+        self.context.remove::<T>()
+    }
+
+    /// Get the event to rewrite it, e.g. in a middleware, the other matchers sharing it are not affected
+    pub fn event_mut(&mut self) -> &mut Event {
+        Arc::make_mut(&mut self.event)
+    }
+
+    pub fn try_get_message_mut(&mut self) -> Option<&mut Message> {
+        match self.event_mut() {
+            Event::MessageEvent(event) => Some(&mut event.message),
+            _ => None,
+        }
+    }
+
     pub fn try_get_user(&self) -> Option<&User> {
         match self.event.as_ref() {
             Event::MessageEvent(event) => Some(&event.sender),
@@ -85,8 +140,17 @@ impl Matcher {
         }
     }
 
+    /// Get the id of the bot, cached by the BotRegistry when the bot is registered in it,
+    /// otherwise `bot_info` is called
+    pub(crate) async fn bot_id(&self) -> Option<String> {
+        match &self.bot_id {
+            Some(id) => id.get(&self.bot).await,
+            None => self.bot.bot_info().await.id,
+        }
+    }
+
     pub async fn is_related_to_bot(&self) -> bool {
-        if let Some(bot_id) = self.bot.bot_info().await.id {
+        if let Some(bot_id) = self.bot_id().await {
             self.is_related_to_user(&bot_id)
         } else {
             tracing::error!("Failed to get bot id.");
@@ -104,13 +168,13 @@ impl Matcher {
                 event::NoticeEvent::GroupHightLightChangeEvent(event) => event
                     .sender
                     .as_ref()
-                    .and_then(|s| Some(s.id == user_id))
+                    .map(|s| s.id == user_id)
                     .unwrap_or(false),
                 event::NoticeEvent::GroupMemberAliasChangeEvent(event) => event.user.id == user_id,
                 event::NoticeEvent::MessageDeletedEvent(event) => event
                     .user
                     .as_ref()
-                    .and_then(|u| Some(u.id == user_id))
+                    .map(|u| u.id == user_id)
                     .unwrap_or(false),
                 _ => false,
             },
@@ -123,9 +187,24 @@ impl Matcher {
         }
     }
 
+    /// Whether a message was sent in reply to the event with `try_send_message` or `try_reply_message`,
+    /// by this Matcher or one of its clones
+    pub fn replied(&self) -> bool {
+        self.replied.load(Ordering::Relaxed)
+    }
+
     pub async fn try_send_message(
         &self,
         message: Vec<MessageSegment>,
+    ) -> Result<Vec<SendMessageResponse>> {
+        let responses = self.send_to_event(message).await?;
+        self.replied.store(true, Ordering::Relaxed);
+        Ok(responses)
+    }
+
+    async fn send_to_event(
+        &self,
+        message: Vec<MessageSegment>,
     ) -> Result<Vec<SendMessageResponse>> {
         match self.event.as_ref() {
             Event::MessageEvent(event) => match event.group.as_ref() {
@@ -0,0 +1,636 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+
+use crate::{
+    command::{Command, CommandContext},
+    concurrency::HandlerLimits,
+    dispatch::{Chat, ConversationKey},
+    filter::{FilterObject, FilterTrait},
+    handler::{
+        ActiveHandlerObject, ActiveHandlerTrait, EventHandlerObject, EventHandlerTrait, Handler,
+        Propagation,
+    },
+    help::{HandlerMetadata, Permission},
+    matcher::Matcher,
+    supervisor::RestartPolicy,
+    utils::storage::JsonStorage,
+};
+
+/// Plugin bundles the handlers and filters of a feature, so it can be enabled and disabled as a whole
+pub struct Plugin {
+    info: PluginInfo,
+    handlers: Vec<Handler>,
+    filters: Vec<FilterObject>,
+}
+
+/// PluginInfo describes a Plugin
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct PluginInfo {
+    pub name: String,
+    pub version: Option<String>,
+    pub description: Option<String>,
+}
+
+impl Plugin {
+    pub fn new<T: Into<String>>(name: T) -> Self {
+        Plugin {
+            info: PluginInfo {
+                name: name.into(),
+                ..Default::default()
+            },
+            handlers: Vec::new(),
+            filters: Vec::new(),
+        }
+    }
+
+    pub fn version<T: Into<String>>(mut self, version: T) -> Self {
+        self.info.version = Some(version.into());
+        self
+    }
+
+    pub fn description<T: Into<String>>(mut self, description: T) -> Self {
+        self.info.description = Some(description.into());
+        self
+    }
+
+    /// Add a handler, its event handler is skipped where the plugin is disabled,
+    /// and its active handler only runs while the plugin isn't disabled everywhere
+    pub fn handler<H: Into<Handler>>(mut self, handler: H) -> Self {
+        self.handlers.push(handler.into());
+        self
+    }
+
+    /// Add a filter, it lets every event pass where the plugin is disabled
+    pub fn filter<F: Into<FilterObject>>(mut self, filter: F) -> Self {
+        self.filters.push(filter.into());
+        self
+    }
+
+    pub fn info(&self) -> &PluginInfo {
+        &self.info
+    }
+
+    /// Split the plugin into its handlers and filters, wrapped to check the PluginRegistry
+    pub(crate) fn into_parts(self, registry: &PluginRegistry) -> (Vec<Handler>, Vec<FilterObject>) {
+        let name = self.info.name.clone();
+        registry.register(self.info);
+        let handlers = self
+            .handlers
+            .into_iter()
+            .map(|handler| Handler {
+                event_handler: handler.event_handler.map(|inner| {
+                    Box::new(PluginEventHandler {
+                        plugin: name.clone(),
+                        registry: registry.clone(),
+                        inner,
+                    }) as EventHandlerObject
+                }),
+                active_handler: handler.active_handler.map(|inner| {
+                    Box::new(PluginActiveHandler {
+                        plugin: name.clone(),
+                        registry: registry.clone(),
+                        inner,
+                    }) as ActiveHandlerObject
+                }),
+            })
+            .collect();
+        let filters = self
+            .filters
+            .into_iter()
+            .map(|inner| {
+                Box::new(PluginFilter {
+                    plugin: name.clone(),
+                    registry: registry.clone(),
+                    inner,
+                }) as FilterObject
+            })
+            .collect();
+        (handlers, filters)
+    }
+}
+
+/// PluginScope is where a Plugin can be disabled
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PluginScope {
+    Group {
+        server: String,
+        id: String,
+    },
+    Private {
+        server: String,
+        id: String,
+    },
+    Bot {
+        server: String,
+        id: String,
+    },
+    /// All the chats of all the bots, the active handlers of the plugin are stopped too
+    Everywhere,
+}
+
+impl PluginScope {
+    /// Get the group or private chat of the event
+    pub fn chat_of(matcher: &Matcher) -> Option<Self> {
+        let key = ConversationKey::from_matcher(matcher)?;
+        let server = key.server.to_string();
+        Some(match key.chat {
+            Chat::Group(id) => PluginScope::Group { server, id },
+            Chat::Private(id) => PluginScope::Private { server, id },
+        })
+    }
+
+    /// Get the bot receiving the event, None if the bot has no id
+    pub async fn bot_of(matcher: &Matcher) -> Option<Self> {
+        Some(PluginScope::Bot {
+            server: matcher.bot.server().to_string(),
+            id: matcher.bot_id().await?,
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            PluginScope::Group { server, id } => json!({ "group": id, "server": server }),
+            PluginScope::Private { server, id } => json!({ "private": id, "server": server }),
+            PluginScope::Bot { server, id } => json!({ "bot": id, "server": server }),
+            PluginScope::Everywhere => json!({ "everywhere": true }),
+        }
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        if value.get("everywhere").and_then(Value::as_bool) == Some(true) {
+            return Some(PluginScope::Everywhere);
+        }
+        let server = value.get("server")?.as_str()?.to_string();
+        let field = |key: &str| Some(value.get(key)?.as_str()?.to_string());
+        if let Some(id) = field("group") {
+            Some(PluginScope::Group { server, id })
+        } else if let Some(id) = field("private") {
+            Some(PluginScope::Private { server, id })
+        } else {
+            field("bot").map(|id| PluginScope::Bot { server, id })
+        }
+    }
+}
+
+#[derive(Default)]
+struct RegistryState {
+    plugins: Vec<PluginInfo>,
+    disabled: HashMap<String, HashSet<PluginScope>>,
+}
+
+/// PluginRegistry keeps the Plugins of an OxideBotManager and where they are disabled.
+/// It's a costless cloneable handle, so the plugins can be enabled and disabled at runtime.
+#[derive(Clone, Default)]
+pub struct PluginRegistry {
+    state: Arc<Mutex<RegistryState>>,
+    storage: JsonStorage,
+    // notified after each update
+    changed: Arc<Notify>,
+}
+
+impl PluginRegistry {
+    fn lock(&self) -> std::sync::MutexGuard<'_, RegistryState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn register(&self, info: PluginInfo) {
+        let mut state = self.lock();
+        state.plugins.retain(|plugin| plugin.name != info.name);
+        state.plugins.push(info);
+    }
+
+    /// Persist the disabled scopes to the JSON file at `path`, and load the ones it already contains
+    pub(crate) async fn set_storage(&self, path: PathBuf) {
+        match self.storage.open(path.clone(), "plugin storage").await {
+            Some(Value::Object(plugins)) => {
+                let mut state = self.lock();
+                for (name, scopes) in plugins {
+                    let scopes = scopes.as_array().into_iter().flatten();
+                    state
+                        .disabled
+                        .entry(name)
+                        .or_default()
+                        .extend(scopes.filter_map(PluginScope::from_json));
+                }
+            }
+            Some(_) => tracing::error!("Invalid plugin storage {}", path.display()),
+            None => {}
+        }
+    }
+
+    /// List the registered plugins
+    pub fn plugins(&self) -> Vec<PluginInfo> {
+        self.lock().plugins.clone()
+    }
+
+    /// List the scopes where the plugin is disabled
+    pub fn disabled_scopes(&self, plugin: &str) -> Vec<PluginScope> {
+        self.lock()
+            .disabled
+            .get(plugin)
+            .map(|scopes| scopes.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether the plugin is enabled for the event, i.e. neither in its chat nor for its bot
+    pub async fn is_enabled(&self, plugin: &str, matcher: &Matcher) -> bool {
+        let (chat_disabled, has_bot_scope) = {
+            let state = self.lock();
+            let Some(scopes) = state.disabled.get(plugin) else {
+                return true;
+            };
+            let chat_disabled = scopes.contains(&PluginScope::Everywhere)
+                || PluginScope::chat_of(matcher).is_some_and(|chat| scopes.contains(&chat));
+            let has_bot_scope = scopes
+                .iter()
+                .any(|scope| matches!(scope, PluginScope::Bot { .. }));
+            (chat_disabled, has_bot_scope)
+        };
+        if chat_disabled {
+            return false;
+        }
+        // looking up the bot id may call the bot, so only do it when needed
+        if !has_bot_scope {
+            return true;
+        }
+        match PluginScope::bot_of(matcher).await {
+            Some(bot) => !self
+                .lock()
+                .disabled
+                .get(plugin)
+                .is_some_and(|scopes| scopes.contains(&bot)),
+            None => true,
+        }
+    }
+
+    fn is_disabled_everywhere(&self, plugin: &str) -> bool {
+        self.lock()
+            .disabled
+            .get(plugin)
+            .is_some_and(|scopes| scopes.contains(&PluginScope::Everywhere))
+    }
+
+    /// Wait until the plugin is disabled everywhere, or until it isn't anymore
+    async fn wait_disabled_everywhere(&self, plugin: &str, disabled: bool) {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            // registered before checking, so an update in between isn't missed
+            changed.as_mut().enable();
+            if self.is_disabled_everywhere(plugin) == disabled {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Enable the plugin in the scope
+    pub async fn enable(&self, plugin: &str, scope: &PluginScope) -> Result<()> {
+        self.update(plugin, |scopes| {
+            scopes.remove(scope);
+        })
+        .await
+    }
+
+    /// Disable the plugin in the scope
+    pub async fn disable(&self, plugin: &str, scope: PluginScope) -> Result<()> {
+        self.update(plugin, |scopes| {
+            scopes.insert(scope);
+        })
+        .await
+    }
+
+    async fn update(
+        &self,
+        plugin: &str,
+        change: impl FnOnce(&mut HashSet<PluginScope>),
+    ) -> Result<()> {
+        let result = self
+            .storage
+            .update(
+                || {
+                    let mut state = self.lock();
+                    if !state.plugins.iter().any(|info| info.name == plugin) {
+                        return Err(anyhow::anyhow!("No such plugin: {}", plugin));
+                    }
+                    change(state.disabled.entry(plugin.to_string()).or_default());
+                    Ok(())
+                },
+                || {
+                    let content = self
+                        .lock()
+                        .disabled
+                        .iter()
+                        .filter(|(_, scopes)| !scopes.is_empty())
+                        .map(|(name, scopes)| {
+                            let scopes = scopes.iter().map(PluginScope::to_json).collect();
+                            (name.clone(), Value::Array(scopes))
+                        })
+                        .collect::<serde_json::Map<_, _>>();
+                    Value::Object(content)
+                },
+            )
+            .await;
+        self.changed.notify_waiters();
+        result
+    }
+}
+
+/// The event handler of a Plugin, skipped where the plugin is disabled
+struct PluginEventHandler {
+    plugin: String,
+    registry: PluginRegistry,
+    inner: EventHandlerObject,
+}
+
+#[async_trait]
+impl EventHandlerTrait for PluginEventHandler {
+    async fn handle_propagation(&self, matcher: Matcher) -> Result<Propagation> {
+        if !self.registry.is_enabled(&self.plugin, &matcher).await {
+            return Ok(Propagation::Continue);
+        }
+        self.inner.handle_propagation(matcher).await
+    }
+
+    fn get_priority(&self) -> u8 {
+        self.inner.get_priority()
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn metadata(&self) -> Vec<HandlerMetadata> {
+        self.inner
+            .metadata()
+            .into_iter()
+            .map(|metadata| HandlerMetadata {
+                plugin: Some(self.plugin.clone()),
+                ..metadata
+            })
+            .collect()
+    }
+
+    fn limits(&self) -> HandlerLimits {
+        self.inner.limits()
+    }
+}
+
+/// The active handler of a Plugin, stopped while the plugin is disabled everywhere
+struct PluginActiveHandler {
+    plugin: String,
+    registry: PluginRegistry,
+    inner: ActiveHandlerObject,
+}
+
+#[async_trait]
+impl ActiveHandlerTrait for PluginActiveHandler {
+    async fn run_forever(&self) -> Result<()> {
+        loop {
+            self.registry
+                .wait_disabled_everywhere(&self.plugin, false)
+                .await;
+            tokio::select! {
+                result = self.inner.run_forever() => return result,
+                _ = self.registry.wait_disabled_everywhere(&self.plugin, true) => {}
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn restart_policy(&self) -> RestartPolicy {
+        self.inner.restart_policy()
+    }
+}
+
+/// The filter of a Plugin, letting every event pass where the plugin is disabled
+struct PluginFilter {
+    plugin: String,
+    registry: PluginRegistry,
+    inner: FilterObject,
+}
+
+#[async_trait]
+impl FilterTrait for PluginFilter {
+    async fn filter(&self, matcher: Matcher) -> bool {
+        if !self.registry.is_enabled(&self.plugin, &matcher).await {
+            return true;
+        }
+        self.inner.filter(matcher).await
+    }
+
+    fn get_priority(&self) -> u8 {
+        self.inner.get_priority()
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.inner.timeout()
+    }
+
+    async fn passed(&self, matcher: &Matcher) {
+        if self.registry.is_enabled(&self.plugin, matcher).await {
+            self.inner.passed(matcher).await
+        }
+    }
+}
+
+/// Create the `plugin` command to list, enable and disable the plugins in the current chat,
+/// for the whole bot with `--bot`, or everywhere with `--everywhere`.
+/// A group chat is managed by its admins, a private chat by its user,
+/// and only the superusers, given as `(server, id)` pairs, may use `--bot` and `--everywhere` or manage any chat.
+pub fn plugin_command(registry: PluginRegistry, superusers: Vec<(String, String)>) -> Command {
+    let list = registry.clone();
+    let superusers = Permission::Users(superusers);
+    let toggle = |name: &str, description: &str, enable: bool| {
+        let registry = registry.clone();
+        let superusers = superusers.clone();
+        Command::new(name)
+            .description(description)
+            .usage("<name> [--bot | --everywhere]")
+            .min_args(1)
+            .max_args(2)
+            .handler(move |ctx: CommandContext| {
+                let registry = registry.clone();
+                let superusers = superusers.clone();
+                async move { toggle_plugin(ctx, registry, &superusers, enable).await }
+            })
+    };
+    Command::new("plugin")
+        .description("Manage the plugins")
+        .subcommand(
+            Command::new("list")
+                .description("List the plugins")
+                .max_args(0)
+                .handler(move |ctx: CommandContext| {
+                    let registry = list.clone();
+                    async move { reply_plugins(ctx, registry).await }
+                }),
+        )
+        .subcommand(toggle("enable", "Enable a plugin here", true))
+        .subcommand(toggle("disable", "Disable a plugin here", false))
+}
+
+async fn reply_plugins(ctx: CommandContext, registry: PluginRegistry) -> Result<()> {
+    let mut text = "Plugins:".to_string();
+    for plugin in registry.plugins() {
+        let state = match registry.is_enabled(&plugin.name, &ctx.matcher).await {
+            true => "enabled",
+            false => "disabled",
+        };
+        text.push_str(&format!("\n{} ({})", plugin.name, state));
+        if let Some(version) = &plugin.version {
+            text.push_str(&format!(" v{}", version));
+        }
+        if let Some(description) = &plugin.description {
+            text.push_str(&format!(" - {}", description));
+        }
+    }
+    ctx.reply(text).await
+}
+
+/// Whether the user who triggered the event may toggle the plugins in the scope
+fn may_toggle(matcher: &Matcher, scope: &PluginScope, superusers: &Permission) -> bool {
+    superusers.allows(matcher)
+        || match scope {
+            PluginScope::Group { .. } => Permission::GroupAdmin.allows(matcher),
+            // the scope is the private chat of the user
+            PluginScope::Private { .. } => true,
+            PluginScope::Bot { .. } | PluginScope::Everywhere => false,
+        }
+}
+
+async fn toggle_plugin(
+    ctx: CommandContext,
+    registry: PluginRegistry,
+    superusers: &Permission,
+    enable: bool,
+) -> Result<()> {
+    let args = ctx.text_args();
+    let Some(name) = args.iter().find(|arg| !arg.starts_with("--")) else {
+        return ctx.reply("Missing the name of the plugin").await;
+    };
+    let scope = match args.iter().find(|arg| arg.starts_with("--")) {
+        None => PluginScope::chat_of(&ctx.matcher),
+        Some(&"--bot") => PluginScope::bot_of(&ctx.matcher).await,
+        Some(&"--everywhere") => Some(PluginScope::Everywhere),
+        Some(option) => return ctx.reply(format!("Unknown option {}", option)).await,
+    };
+    let Some(scope) = scope else {
+        return ctx.reply("The plugin can't be toggled here").await;
+    };
+    if !may_toggle(&ctx.matcher, &scope, superusers) {
+        return ctx
+            .reply("You are not permitted to toggle the plugin there")
+            .await;
+    }
+    let result = match enable {
+        true => registry.enable(name, &scope).await,
+        false => registry.disable(name, scope).await,
+    };
+    match result {
+        Ok(()) if enable => ctx.reply(format!("Plugin {} enabled", name)).await,
+        Ok(()) => ctx.reply(format!("Plugin {} disabled", name)).await,
+        Err(e) => ctx.reply(e.to_string()).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::testing::message;
+
+    #[tokio::test]
+    async fn active_handlers_stop_while_disabled_everywhere() {
+        let registry = PluginRegistry::default();
+        let started = Arc::new(AtomicUsize::new(0));
+        let counter = started.clone();
+        let plugin = Plugin::new("clock").handler(Handler::active(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            std::future::pending::<Result<()>>()
+        }));
+        let (mut handlers, _) = plugin.into_parts(&registry);
+        let active = handlers.remove(0).active_handler.unwrap();
+        let task = tokio::spawn(async move { active.run_forever().await });
+        let settle = || tokio::time::sleep(Duration::from_millis(20));
+
+        settle().await;
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+        let group = PluginScope::chat_of(&message(Some("g"), "1", "x")).unwrap();
+        registry.disable("clock", group).await.unwrap();
+        registry
+            .disable("clock", PluginScope::Everywhere)
+            .await
+            .unwrap();
+        settle().await;
+        registry
+            .enable("clock", &PluginScope::Everywhere)
+            .await
+            .unwrap();
+        settle().await;
+        // restarted once, disabling it in a group didn't stop it
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn the_help_menu_leaves_out_the_disabled_plugins() {
+        let command = |name: &str| {
+            crate::command::CommandRouter::new()
+                .command(Command::new(name).handler(|_: CommandContext| async { Ok(()) }))
+        };
+        let manager = crate::OxideBotManager::new()
+            .handler(command("ping"))
+            .plugin(Plugin::new("weather").handler(command("weather")));
+        let menu = manager.help_menu();
+        let registry = manager.plugin_registry();
+        let group = message(Some("g"), "1", "x");
+        registry
+            .disable("weather", PluginScope::chat_of(&group).unwrap())
+            .await
+            .unwrap();
+
+        let names = |entries: Vec<HandlerMetadata>| {
+            entries
+                .into_iter()
+                .map(|entry| entry.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(menu.permitted(&group).await), ["/ping"]);
+        let other = message(Some("h"), "1", "x");
+        assert_eq!(names(menu.permitted(&other).await), ["/ping", "/weather"]);
+    }
+
+    #[test]
+    fn chats_are_toggled_by_who_manages_them() {
+        let superusers = Permission::Users(vec![("test".to_string(), "root".to_string())]);
+        let private = message(None, "1", "x");
+        let private_scope = PluginScope::chat_of(&private).unwrap();
+        assert!(may_toggle(&private, &private_scope, &superusers));
+        assert!(!may_toggle(&private, &PluginScope::Everywhere, &superusers));
+
+        let member = message(Some("g"), "1", "x");
+        let group_scope = PluginScope::chat_of(&member).unwrap();
+        assert!(!may_toggle(&member, &group_scope, &superusers));
+        let root = message(Some("g"), "root", "x");
+        assert!(may_toggle(&root, &group_scope, &superusers));
+        assert!(may_toggle(&root, &PluginScope::Everywhere, &superusers));
+    }
+}
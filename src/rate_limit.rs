@@ -0,0 +1,391 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::time::Instant;
+
+use crate::{filter::FilterTrait, matcher::Matcher, source::message::MessageSegment};
+
+const PRUNE_INTERVAL: u64 = 1024;
+/// The end of a cooldown too long to be represented, it's as good as never
+const NEVER: Duration = Duration::from_secs(60 * 60 * 24 * 365 * 30);
+
+/// RateLimitKey decides what a RateLimit counts the events by, always within the server of the bot
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum RateLimitKey {
+    /// The user who triggered the event
+    #[default]
+    User,
+    /// The group of the event, the private events are not limited
+    Group,
+    /// The command of the message, e.g. `/roll`, see `RateLimit::command_prefixes`. The other events are not limited
+    Command,
+}
+
+type Key = (&'static str, String);
+/// The server and id of the user, and the name of the command
+type CooldownKey = (&'static str, String, String);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Cooldowns keeps the per-user cooldowns of the commands, keyed by the server and id of the user.
+/// It's a costless cloneable handle, share it with the handlers (e.g. with `OxideBotManager::extension`)
+/// to check or start a cooldown themselves.
+#[derive(Clone, Default)]
+pub struct Cooldowns {
+    until: Arc<Mutex<HashMap<CooldownKey, Instant>>>,
+    started: Arc<AtomicU64>,
+}
+
+impl Cooldowns {
+    fn key(matcher: &Matcher, name: &str) -> Option<CooldownKey> {
+        let user = matcher.try_get_user()?;
+        Some((matcher.bot.server(), user.id.clone(), name.to_string()))
+    }
+
+    /// The time left before the user who triggered the event can use `name` again, None if it's not cooling down
+    pub fn remaining(&self, matcher: &Matcher, name: &str) -> Option<Duration> {
+        let key = Self::key(matcher, name)?;
+        let until = *self
+            .until
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&key)?;
+        let remaining = until.saturating_duration_since(Instant::now());
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    /// Start the cooldown of `name` for the user who triggered the event,
+    /// or return the time left if it's still cooling down.
+    /// The events without a user are never cooling down.
+    pub fn try_start(
+        &self,
+        matcher: &Matcher,
+        name: &str,
+        cooldown: Duration,
+    ) -> Result<(), Duration> {
+        let Some(key) = Self::key(matcher, name) else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let mut until = self.until.lock().unwrap_or_else(|e| e.into_inner());
+        if self.started.fetch_add(1, Ordering::Relaxed) % PRUNE_INTERVAL == 0 {
+            until.retain(|_, until| *until > now);
+        }
+        if let Some(until) = until.get(&key).filter(|until| **until > now) {
+            return Err(*until - now);
+        }
+        let end = now.checked_add(cooldown).unwrap_or(now + NEVER);
+        until.insert(key, end);
+        Ok(())
+    }
+
+    /// Clear the cooldown of `name` for the user who triggered the event
+    pub fn reset(&self, matcher: &Matcher, name: &str) {
+        if let Some(key) = Self::key(matcher, name) {
+            self.until
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&key);
+        }
+    }
+}
+
+/// RateLimit is a built-in filter throttling the events with a token bucket for each user, group or command:
+/// a bucket holds up to `capacity` events and gets one back every `refill`.
+/// It can also enforce per-user cooldowns of commands, and tell the users to slow down.
+pub struct RateLimit {
+    key: RateLimitKey,
+    capacity: u32,
+    refill: Duration,
+    cooldown_rules: HashMap<String, Duration>,
+    cooldowns: Cooldowns,
+    command_prefixes: Vec<String>,
+    reply: Option<String>,
+    reply_interval: Duration,
+    priority: u8,
+    buckets: Mutex<HashMap<Key, Bucket>>,
+    replied: Mutex<HashMap<Key, Instant>>,
+    checked: AtomicU64,
+}
+
+impl RateLimit {
+    /// Allow bursts of `capacity` events for each key, then one event every `refill`
+    pub fn new(key: RateLimitKey, capacity: u32, refill: Duration) -> Self {
+        RateLimit {
+            key,
+            capacity: capacity.max(1),
+            refill,
+            cooldown_rules: HashMap::new(),
+            cooldowns: Cooldowns::default(),
+            command_prefixes: vec!["/".to_string()],
+            reply: None,
+            reply_interval: Duration::from_secs(30),
+            priority: 16,
+            buckets: Mutex::new(HashMap::new()),
+            replied: Mutex::new(HashMap::new()),
+            checked: AtomicU64::new(0),
+        }
+    }
+
+    pub fn per_user(capacity: u32, refill: Duration) -> Self {
+        Self::new(RateLimitKey::User, capacity, refill)
+    }
+
+    pub fn per_group(capacity: u32, refill: Duration) -> Self {
+        Self::new(RateLimitKey::Group, capacity, refill)
+    }
+
+    pub fn per_command(capacity: u32, refill: Duration) -> Self {
+        Self::new(RateLimitKey::Command, capacity, refill)
+    }
+
+    /// Let each user use `command` (the first word of the message, e.g. `/roll`) only once every `cooldown`.
+    /// The cooldown starts once the event passed all the filters.
+    pub fn cooldown<T: Into<String>>(mut self, command: T, cooldown: Duration) -> Self {
+        self.cooldown_rules.insert(command.into(), cooldown);
+        self
+    }
+
+    /// Replace the prefixes of the commands, `/` by default: the first word of a message is a command
+    /// if it starts with one of them or has a cooldown, other messages are not counted by `RateLimitKey::Command`
+    pub fn command_prefixes(mut self, prefixes: Vec<String>) -> Self {
+        self.command_prefixes = prefixes;
+        self
+    }
+
+    /// Reply the message and the time to wait to the users being limited.
+    /// The reply itself is sent at most once every `reply_interval` (30 seconds by default) for the same key.
+    pub fn reply<T: Into<String>>(mut self, message: T) -> Self {
+        self.reply = Some(message.into());
+        self
+    }
+
+    pub fn reply_interval(mut self, reply_interval: Duration) -> Self {
+        self.reply_interval = reply_interval;
+        self
+    }
+
+    /// Set the priority of the filter, 16 by default so it runs before the filters with the `DEFAULT_PRIORITY`
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Get the Cooldowns of this filter, so the handlers can check, start or reset the cooldowns
+    pub fn cooldowns(&self) -> Cooldowns {
+        self.cooldowns.clone()
+    }
+
+    /// Get the command of the message, if its first word is one
+    fn command_of(&self, matcher: &Matcher) -> Option<String> {
+        let text = matcher.try_get_message()?.get_raw_text();
+        let word = text.split_whitespace().next()?;
+        let prefixed = self
+            .command_prefixes
+            .iter()
+            .any(|prefix| word.len() > prefix.len() && word.starts_with(prefix.as_str()));
+        (prefixed || self.cooldown_rules.contains_key(word)).then(|| word.to_string())
+    }
+
+    fn key_of(&self, matcher: &Matcher) -> Option<String> {
+        match self.key {
+            RateLimitKey::User => matcher.try_get_user().map(|user| user.id.clone()),
+            RateLimitKey::Group => matcher.try_get_group().map(|group| group.id.clone()),
+            RateLimitKey::Command => self.command_of(matcher),
+        }
+    }
+
+    /// Take a token from the bucket of the key, or return the time until the next token
+    fn take(&self, key: &Key) -> Result<(), Duration> {
+        let now = Instant::now();
+        let capacity = self.capacity as f64;
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if self.checked.fetch_add(1, Ordering::Relaxed) % PRUNE_INTERVAL == 0 {
+            // a bucket idle for that long is full again, it's the same as a new one
+            let full_after = self.refill.saturating_mul(self.capacity);
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < full_after);
+            self.replied
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .retain(|_, replied| now.duration_since(*replied) < self.reply_interval);
+        }
+
+        let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        if !self.refill.is_zero() {
+            let refilled =
+                now.duration_since(bucket.updated).as_secs_f64() / self.refill.as_secs_f64();
+            bucket.tokens = (bucket.tokens + refilled).min(capacity);
+        } else {
+            bucket.tokens = capacity;
+        }
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(self.refill.mul_f64(1.0 - bucket.tokens))
+        }
+    }
+
+    /// Reply to the user being limited, unless they were already told within the reply interval
+    async fn reply_limited(&self, matcher: &Matcher, key: Key, wait: Duration) {
+        let Some(message) = &self.reply else {
+            return;
+        };
+        {
+            let mut replied = self.replied.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            if replied
+                .get(&key)
+                .is_some_and(|replied| now.duration_since(*replied) < self.reply_interval)
+            {
+                return;
+            }
+            replied.insert(key, now);
+        }
+        let reply = format!("{} (try again in {}s)", message, wait.as_secs().max(1));
+        if let Err(e) = matcher
+            .try_send_message(vec![MessageSegment::text(reply)])
+            .await
+        {
+            tracing::debug!("Failed to reply to the rate limited user: {:?}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl FilterTrait for RateLimit {
+    async fn filter(&self, matcher: Matcher) -> bool {
+        let server = matcher.bot.server();
+        if let Some(key) = self.key_of(&matcher) {
+            let key = (server, key);
+            if let Err(wait) = self.take(&key) {
+                tracing::debug!("Rate limited {:?} {} on {}", self.key, key.1, server);
+                self.reply_limited(&matcher, key, wait).await;
+                return false;
+            }
+        }
+
+        let Some(command) = self.command_of(&matcher) else {
+            return true;
+        };
+        if !self.cooldown_rules.contains_key(&command) {
+            return true;
+        }
+        // only checked here, it's started once the event passed the other filters too
+        match self.cooldowns.remaining(&matcher, &command) {
+            None => true,
+            Some(wait) => {
+                let user = matcher.try_get_user().map(|user| user.id.as_str());
+                let key = (server, format!("{} {}", command, user.unwrap_or_default()));
+                self.reply_limited(&matcher, key, wait).await;
+                false
+            }
+        }
+    }
+
+    async fn passed(&self, matcher: &Matcher) {
+        let Some(command) = self.command_of(matcher) else {
+            return;
+        };
+        if let Some(cooldown) = self.cooldown_rules.get(&command) {
+            // a concurrent event of the user may have started it meanwhile, this one still passes
+            let _ = self.cooldowns.try_start(matcher, &command, *cooldown);
+        }
+    }
+
+    fn get_priority(&self) -> u8 {
+        self.priority
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filter::FilterPool, testing::message};
+
+    fn key(id: &str) -> Key {
+        ("test", id.to_string())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn buckets_allow_bursts_then_refill() {
+        let limit = RateLimit::per_user(2, Duration::from_millis(40));
+        assert!(limit.take(&key("1")).is_ok());
+        assert!(limit.take(&key("1")).is_ok());
+        let wait = limit.take(&key("1")).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(40));
+        // the other keys have their own bucket
+        assert!(limit.take(&key("2")).is_ok());
+        tokio::time::advance(Duration::from_millis(40)).await;
+        assert!(limit.take(&key("1")).is_ok());
+        assert!(limit.take(&key("1")).is_err());
+    }
+
+    #[test]
+    fn buckets_without_refill_time_never_empty() {
+        let limit = RateLimit::per_user(1, Duration::ZERO);
+        for _ in 0..10 {
+            assert!(limit.take(&key("1")).is_ok());
+        }
+    }
+
+    #[test]
+    fn commands_need_a_prefix_or_a_cooldown() {
+        let limit = RateLimit::per_command(1, Duration::from_secs(60))
+            .cooldown("roll", Duration::from_secs(1));
+        let command = |text: &str| limit.command_of(&message(Some("g"), "1", text));
+        assert_eq!(command("/draw a cat").as_deref(), Some("/draw"));
+        assert_eq!(command("roll 6").as_deref(), Some("roll"));
+        assert_eq!(command("hello there"), None);
+        assert_eq!(command("/ alone"), None);
+    }
+
+    #[tokio::test]
+    async fn cooldowns_start_after_all_filters_passed() {
+        let limit =
+            RateLimit::per_user(100, Duration::ZERO).cooldown("/draw", Duration::from_secs(60));
+        let cooldowns = limit.cooldowns();
+        let mut pool = FilterPool::new();
+        pool.add_filter(Box::new(limit));
+        pool.add_filter(Box::new(|matcher: Matcher| async move {
+            matcher.try_get_group().is_some()
+        }));
+
+        let private = message(None, "1", "/draw");
+        assert!(!pool.filter(private.clone()).await);
+        assert_eq!(cooldowns.remaining(&private, "/draw"), None);
+
+        let group = message(Some("g"), "1", "/draw");
+        assert!(pool.filter(group.clone()).await);
+        assert!(cooldowns.remaining(&group, "/draw").is_some());
+        assert!(!pool.filter(group).await);
+    }
+
+    #[tokio::test]
+    async fn endless_cooldowns_dont_overflow() {
+        let cooldowns = Cooldowns::default();
+        let matcher = message(Some("g"), "1", "/draw");
+        assert!(cooldowns
+            .try_start(&matcher, "/draw", Duration::MAX)
+            .is_ok());
+        assert!(cooldowns.remaining(&matcher, "/draw").unwrap() > Duration::from_secs(3600));
+        assert!(cooldowns
+            .try_start(&matcher, "/draw", Duration::MAX)
+            .is_err());
+    }
+}
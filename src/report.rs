@@ -0,0 +1,182 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::{
+    error::HandlerError, handler::Propagation, matcher::Matcher, source::message::MessageSegment,
+};
+
+/// HandlerOutcome is how a run of an event handler ended
+#[derive(Debug)]
+pub enum HandlerOutcome {
+    /// The event handler finished with the Propagation
+    Finished(Propagation),
+    /// The event handler was busy and the event was dropped, see `HandlerLimits::overflow`
+    Dropped,
+    /// The event handler returned an error, panicked or timed out, it was already passed to the error hooks
+    Failed(HandlerError),
+}
+
+/// HandlerRun is the record of an event handler that ran for an event
+#[derive(Debug)]
+pub struct HandlerRun {
+    pub handler: String,
+    pub priority: u8,
+    pub outcome: HandlerOutcome,
+    /// The time spent handling the event, not counting the wait for its turn or concurrency permits
+    pub duration: Duration,
+}
+
+/// DispatchReport lists the event handlers that ran for an event, in the order they finished.
+/// The event handlers skipped because one running before them returned `Propagation::Stop` are not listed.
+#[derive(Debug, Default)]
+pub struct DispatchReport {
+    pub runs: Vec<HandlerRun>,
+    /// The time from the first event handler starting to the last one finishing
+    pub duration: Duration,
+    /// Whether a message was sent in reply to the event, see `Matcher::replied`
+    pub replied: bool,
+}
+
+impl DispatchReport {
+    /// Whether an event handler consumed the event by returning `Propagation::Stop`
+    pub fn consumed(&self) -> bool {
+        self.runs
+            .iter()
+            .any(|run| matches!(run.outcome, HandlerOutcome::Finished(Propagation::Stop)))
+    }
+
+    /// Whether the event was answered: an event handler consumed it or a reply was sent.
+    /// The messages sent by calling the bot directly instead of the Matcher are not seen.
+    pub fn answered(&self) -> bool {
+        self.consumed() || self.replied
+    }
+
+    /// The runs of the event handlers that failed
+    pub fn failures(&self) -> impl Iterator<Item = &HandlerRun> {
+        self.runs
+            .iter()
+            .filter(|run| matches!(run.outcome, HandlerOutcome::Failed(_)))
+    }
+}
+
+/// AfterHook is called with the DispatchReport once all event handlers finished with an event that passed the filters.
+/// The after hooks are called in order of addition, use them to record metrics or send a fallback reply.
+#[async_trait]
+pub trait AfterHookTrait: Send + Sync {
+    async fn after(&self, report: &DispatchReport, matcher: Matcher);
+}
+
+pub type AfterHookObject = Box<dyn AfterHookTrait>;
+
+impl<A: AfterHookTrait + 'static> From<A> for AfterHookObject {
+    fn from(after_hook: A) -> Self {
+        Box::new(after_hook)
+    }
+}
+
+/// FallbackReply is a built-in after hook replying to a message that no event handler answered, see `DispatchReport::answered`
+pub struct FallbackReply {
+    pub message: String,
+    /// Only reply to the messages mentioning the bot, so the other messages in groups are left alone, it's true by default
+    pub mentioned_only: bool,
+}
+
+impl Default for FallbackReply {
+    fn default() -> Self {
+        FallbackReply {
+            message: "Sorry, I didn't understand that.".to_string(),
+            mentioned_only: true,
+        }
+    }
+}
+
+impl FallbackReply {
+    pub fn new<T: Into<String>>(message: T) -> Self {
+        FallbackReply {
+            message: message.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn mentioned_only(mut self, mentioned_only: bool) -> Self {
+        self.mentioned_only = mentioned_only;
+        self
+    }
+}
+
+#[async_trait]
+impl AfterHookTrait for FallbackReply {
+    async fn after(&self, report: &DispatchReport, matcher: Matcher) {
+        if report.answered() || report.failures().next().is_some() {
+            return;
+        }
+        if matcher.try_get_message().is_none()
+            || (self.mentioned_only && !matcher.is_related_to_bot().await)
+        {
+            return;
+        }
+        if let Err(e) = matcher
+            .try_send_message(vec![MessageSegment::text(self.message.clone())])
+            .await
+        {
+            tracing::debug!("Failed to send the fallback reply: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handler::{EventHandlerPool, Handler},
+        testing::{message_to, TestBot},
+    };
+
+    #[tokio::test]
+    async fn fallback_only_answers_unanswered_messages() {
+        let bot = TestBot::new("bot");
+        let fallback = FallbackReply::default().mentioned_only(false);
+        let report = DispatchReport::default();
+
+        let replied = message_to(&bot, Some("g"), "1", "hi");
+        replied
+            .try_send_message(vec![MessageSegment::text("hello")])
+            .await
+            .unwrap();
+        let report_replied = DispatchReport {
+            replied: replied.replied(),
+            ..Default::default()
+        };
+        fallback.after(&report_replied, replied).await;
+        assert_eq!(bot.sent.lock().unwrap().len(), 1);
+
+        fallback
+            .after(&report, message_to(&bot, Some("g"), "1", "hi"))
+            .await;
+        assert_eq!(bot.sent.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn fallback_only_answers_mentions_by_default() {
+        let bot = TestBot::new("bot");
+        FallbackReply::default()
+            .after(
+                &DispatchReport::default(),
+                message_to(&bot, Some("g"), "1", "hi"),
+            )
+            .await;
+        assert!(bot.sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn after_hooks_run_once_the_handlers_finished() {
+        let bot = TestBot::new("bot");
+        let mut pool = EventHandlerPool::new();
+        pool.add_handler(Handler::from_fn(|_matcher: Matcher| async { Ok(()) }));
+        pool.add_after_hook(FallbackReply::new("?").mentioned_only(false).into());
+        pool.handle(message_to(&bot, Some("g"), "1", "hi"), None)
+            .await;
+        assert_eq!(bot.sent.lock().unwrap()[0].1, [MessageSegment::text("?")]);
+    }
+}
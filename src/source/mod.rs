@@ -1,4 +1,4 @@
+pub mod bot;
 pub mod group;
 pub mod message;
 pub mod user;
-pub mod bot;
\ No newline at end of file
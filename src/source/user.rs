@@ -27,11 +27,12 @@ pub struct UserGroupInfo {
     pub level: Option<String>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Default)]
 pub enum Sex {
     Male,
     Female,
     Other,
+    #[default]
     Unknown,
 }
 
@@ -47,12 +48,6 @@ impl From<&str> for Sex {
     }
 }
 
-impl Default for Sex {
-    fn default() -> Self {
-        Self::Unknown
-    }
-}
-
 #[derive(Clone, Debug, PartialEq, Default)]
 pub struct UserProfile {
     pub nickname: Option<String>,
@@ -0,0 +1,172 @@
+use std::time::{Duration, Instant};
+
+/// BackoffPolicy decides how long to wait before restarting a stopped task, and when to give up.
+/// The delay starts at `initial_delay` and is multiplied by `multiplier` after every failed attempt, up to `max_delay`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackoffPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    /// Give up after this many restarts in a row, None means retry forever.
+    pub max_retries: Option<u32>,
+    /// A task that ran at least this long is considered healthy, and the attempt counter is reset.
+    pub reset_after: Duration,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        BackoffPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            max_retries: None,
+            reset_after: Duration::from_secs(60),
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// Get the delay before the `attempt`th restart (starting from 1)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .max(1.0)
+            .powi(attempt.saturating_sub(1).min(i32::MAX as u32) as i32);
+        self.initial_delay
+            .mul_f64(factor.min(u32::MAX as f64))
+            .min(self.max_delay)
+    }
+
+    /// Whether the `attempt`th restart (starting from 1) is allowed
+    pub fn should_retry(&self, attempt: u32) -> bool {
+        self.max_retries
+            .map_or(true, |max_retries| attempt <= max_retries)
+    }
+}
+
+/// Restarts counts the restarts in a row of a supervised task, for the supervisors of the bots and active handlers
+#[derive(Debug, Default)]
+pub(crate) struct Restarts {
+    attempt: u32,
+}
+
+impl Restarts {
+    /// Count a restart of the task that ran since `started`, and get the attempt with the delay to wait before it,
+    /// or None if the BackoffPolicy gives up
+    pub(crate) fn next(
+        &mut self,
+        backoff: &BackoffPolicy,
+        started: Instant,
+    ) -> Option<(u32, Duration)> {
+        if started.elapsed() >= backoff.reset_after {
+            self.attempt = 0;
+        }
+        self.attempt += 1;
+        backoff
+            .should_retry(self.attempt)
+            .then(|| (self.attempt, backoff.delay(self.attempt)))
+    }
+}
+
+/// RestartPolicy decides whether a finished task should be restarted
+#[derive(Clone, Debug, PartialEq, Default)]
+pub enum RestartPolicy {
+    /// Never restart the task
+    #[default]
+    Never,
+    /// Restart the task with the BackoffPolicy only when it returns an error or panics
+    OnError(BackoffPolicy),
+    /// Restart the task with the BackoffPolicy whenever it finishes
+    Always(BackoffPolicy),
+}
+
+impl RestartPolicy {
+    /// Get the BackoffPolicy to restart the task with, or None if it should not be restarted
+    pub fn backoff(&self, failed: bool) -> Option<&BackoffPolicy> {
+        match self {
+            RestartPolicy::Never => None,
+            RestartPolicy::OnError(backoff) if failed => Some(backoff),
+            RestartPolicy::OnError(_) => None,
+            RestartPolicy::Always(backoff) => Some(backoff),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_grows_up_to_max_delay() {
+        let backoff = BackoffPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.0,
+            ..Default::default()
+        };
+        let delays: Vec<u64> = (1..=6)
+            .map(|attempt| backoff.delay(attempt).as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        assert_eq!(backoff.delay(0), Duration::from_secs(1));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn delay_never_shrinks() {
+        let backoff = BackoffPolicy {
+            multiplier: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(backoff.delay(5), backoff.initial_delay);
+    }
+
+    #[test]
+    fn restarts_give_up_after_max_retries() {
+        let backoff = BackoffPolicy {
+            max_retries: Some(2),
+            ..Default::default()
+        };
+        let mut restarts = Restarts::default();
+        let started = Instant::now();
+        assert_eq!(
+            restarts.next(&backoff, started),
+            Some((1, Duration::from_secs(1)))
+        );
+        assert_eq!(
+            restarts.next(&backoff, started),
+            Some((2, Duration::from_secs(2)))
+        );
+        assert_eq!(restarts.next(&backoff, started), None);
+    }
+
+    #[test]
+    fn restarts_reset_after_a_healthy_run() {
+        let backoff = BackoffPolicy {
+            reset_after: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut restarts = Restarts::default();
+        for _ in 0..3 {
+            assert_eq!(
+                restarts
+                    .next(&backoff, Instant::now())
+                    .map(|(attempt, _)| attempt),
+                Some(1)
+            );
+        }
+    }
+
+    #[test]
+    fn restart_policy_backoff() {
+        let backoff = BackoffPolicy::default();
+        assert!(RestartPolicy::Never.backoff(true).is_none());
+        assert!(RestartPolicy::OnError(backoff.clone())
+            .backoff(false)
+            .is_none());
+        assert!(RestartPolicy::OnError(backoff.clone())
+            .backoff(true)
+            .is_some());
+        assert!(RestartPolicy::Always(backoff).backoff(false).is_some());
+    }
+}
@@ -0,0 +1,282 @@
+//! Mock bots and events shared by the unit tests
+
+use std::{
+    any::Any,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::{
+    api::{payload::SendMessageTarget, CallApiTrait, SendMessageResponse},
+    bot::{BotObject, BotTrait},
+    channel::EventSender,
+    event::{Event, EventObject, EventTrait, MessageEvent},
+    handler::Handler,
+    matcher::Matcher,
+    source::{
+        bot::BotInfo,
+        group::Group,
+        message::{Message, MessageSegment},
+        user::User,
+    },
+};
+
+/// The messages sent by a TestBot with their target
+pub(crate) type Sent = Arc<Mutex<Vec<(SendMessageTarget, Vec<MessageSegment>)>>>;
+
+#[derive(Clone, Default)]
+pub(crate) struct TestBot {
+    pub(crate) id: Option<String>,
+    pub(crate) sent: Sent,
+    /// Sending a message with this text fails
+    pub(crate) failing: Option<String>,
+    /// The EventSender given to the last `start_sending_events`
+    pub(crate) sender: Arc<Mutex<Option<EventSender>>>,
+    /// The number of `start_sending_events` tasks running
+    pub(crate) running: Arc<AtomicUsize>,
+}
+
+impl TestBot {
+    pub(crate) fn new(id: &str) -> Self {
+        TestBot {
+            id: Some(id.to_string()),
+            sent: Sent::default(),
+            failing: None,
+            sender: Arc::default(),
+            running: Arc::default(),
+        }
+    }
+
+    /// Wait for `start_sending_events` to be called and get its EventSender
+    pub(crate) async fn sender(&self) -> EventSender {
+        loop {
+            if let Some(sender) = self.sender.lock().unwrap().clone() {
+                return sender;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    /// Wait until `running` tasks of `start_sending_events` are running
+    pub(crate) async fn wait_running(&self, running: usize) {
+        wait_count(&self.running, running).await
+    }
+}
+
+/// Wait until the counter reaches `count`
+pub(crate) async fn wait_count(counter: &AtomicUsize, count: usize) {
+    while counter.load(Ordering::SeqCst) != count {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+}
+
+/// Running counts a running task until it's dropped, e.g. when the task is aborted
+pub(crate) struct Running(Arc<AtomicUsize>);
+
+impl Running {
+    pub(crate) fn new(running: &Arc<AtomicUsize>) -> Self {
+        running.fetch_add(1, Ordering::SeqCst);
+        Running(running.clone())
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[async_trait::async_trait]
+impl CallApiTrait for TestBot {
+    async fn send_message(
+        &self,
+        message: Vec<MessageSegment>,
+        target: SendMessageTarget,
+    ) -> Result<Vec<SendMessageResponse>> {
+        let failing = message.iter().any(|segment| {
+            matches!(segment, MessageSegment::Text { content } if Some(content) == self.failing.as_ref())
+        });
+        if failing {
+            return Err(anyhow::anyhow!("Failed to send"));
+        }
+        self.sent.lock().unwrap().push((target, message));
+        Ok(vec![SendMessageResponse {
+            sent_message_id: String::new(),
+        }])
+    }
+}
+
+#[async_trait::async_trait]
+impl BotTrait for TestBot {
+    async fn bot_info(&self) -> BotInfo {
+        BotInfo {
+            id: self.id.clone(),
+            nickname: None,
+        }
+    }
+
+    async fn start_sending_events(&self, sender: EventSender) {
+        let _running = Running::new(&self.running);
+        *self.sender.lock().unwrap() = Some(sender);
+        std::future::pending::<()>().await
+    }
+
+    fn server(&self) -> &'static str {
+        "test"
+    }
+
+    fn clone_box(&self) -> BotObject {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct TestEvent(pub(crate) Event);
+
+impl EventTrait for TestEvent {
+    fn get_events(&self) -> Vec<Event> {
+        vec![self.0.clone()]
+    }
+
+    fn server(&self) -> &'static str {
+        "test"
+    }
+
+    fn clone_box(&self) -> EventObject {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// A message of the user received by the bot, in the group or in private
+pub(crate) fn message_to(bot: &TestBot, group: Option<&str>, user: &str, text: &str) -> Matcher {
+    let event = MessageEvent {
+        sender: User {
+            id: user.to_string(),
+            ..Default::default()
+        },
+        group: group.map(|id| Group {
+            id: id.to_string(),
+            ..Default::default()
+        }),
+        message: Message {
+            segments: vec![MessageSegment::text(text)],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    Matcher::new(
+        Box::new(TestEvent(Event::MessageEvent(event))),
+        Box::new(bot.clone()),
+    )
+    .remove(0)
+}
+
+/// A message of the user received by a TestBot with the id "bot"
+pub(crate) fn message(group: Option<&str>, user: &str, text: &str) -> Matcher {
+    message_to(&TestBot::new("bot"), group, user, text)
+}
+
+/// Recorder is an event handler keeping the matchers of the events it handled
+#[derive(Clone, Default)]
+pub(crate) struct Recorder {
+    pub(crate) handled: Arc<Mutex<Vec<Matcher>>>,
+}
+
+impl Recorder {
+    pub(crate) fn handler(&self) -> Handler {
+        let handled = self.handled.clone();
+        Handler::from_fn(move |matcher: Matcher| {
+            let handled = handled.clone();
+            async move {
+                handled.lock().unwrap().push(matcher);
+                Ok(())
+            }
+        })
+    }
+
+    /// The raw texts of the handled messages
+    pub(crate) fn texts(&self) -> Vec<String> {
+        self.handled
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|matcher| matcher.try_get_message())
+            .map(|message| message.get_raw_text())
+            .collect()
+    }
+
+    /// Wait until `count` events were handled
+    pub(crate) async fn wait(&self, count: usize) {
+        while self.handled.lock().unwrap().len() < count {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+}
+
+/// Logs keeps the events logged on the current thread while its guard is alive, as `LEVEL message`
+#[derive(Clone, Default)]
+pub(crate) struct Logs {
+    lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl Logs {
+    pub(crate) fn capture() -> (Self, tracing::subscriber::DefaultGuard) {
+        let logs = Logs::default();
+        let guard = tracing::subscriber::set_default(logs.clone());
+        (logs, guard)
+    }
+
+    pub(crate) fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().clone()
+    }
+}
+
+struct MessageVisitor(String);
+
+impl tracing::field::Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+impl tracing::Subscriber for Logs {
+    fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, _span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        tracing::span::Id::from_u64(1)
+    }
+
+    fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+    fn event(&self, event: &tracing::Event<'_>) {
+        let mut message = MessageVisitor(String::new());
+        event.record(&mut message);
+        self.lines
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", event.metadata().level(), message.0));
+    }
+
+    fn enter(&self, _span: &tracing::span::Id) {}
+
+    fn exit(&self, _span: &tracing::span::Id) {}
+}
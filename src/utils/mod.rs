@@ -1 +1,2 @@
+pub(crate) mod storage;
 pub mod wait;
@@ -0,0 +1,111 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+
+/// JsonStorage is the JSON file that the state of a costless cloneable handle is persisted to, e.g. the AccessList.
+/// The updates are written in order, and each of them replaces the file at once,
+/// so a crash while writing never leaves a truncated file behind.
+#[derive(Clone, Default)]
+pub(crate) struct JsonStorage {
+    path: Arc<Mutex<Option<PathBuf>>>,
+    // the file is written in the order of the updates
+    saving: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl JsonStorage {
+    fn path(&self) -> Option<PathBuf> {
+        self.path.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Persist to the file at `path` from now on, and read the value it already contains.
+    /// `name` describes the file in the logged errors, None is returned if it doesn't exist or can't be read.
+    pub(crate) async fn open(&self, path: PathBuf, name: &str) -> Option<Value> {
+        let value = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => match serde_json::from_str::<Value>(&content) {
+                Ok(value) => Some(value),
+                Err(_) => {
+                    tracing::error!("Invalid {} {}", name, path.display());
+                    None
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                tracing::error!("Failed to read {} {}: {:?}", name, path.display(), e);
+                None
+            }
+        };
+        *self.path.lock().unwrap_or_else(|e| e.into_inner()) = Some(path);
+        value
+    }
+
+    /// Apply the change to the state, then write the content of the new state if a file is set.
+    /// No other update runs in between, so the file always ends up with the latest state.
+    pub(crate) async fn update(
+        &self,
+        change: impl FnOnce() -> Result<()>,
+        content: impl FnOnce() -> Value,
+    ) -> Result<()> {
+        let _saving = self.saving.lock().await;
+        change()?;
+        match self.path() {
+            Some(path) => write(&path, content().to_string()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Write the content to a temporary file next to `path`, then rename it to `path`
+async fn write(path: &Path, content: String) -> Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut file = tokio::fs::File::create(&temporary).await?;
+    file.write_all(content.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&temporary, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn updates_replace_the_file() {
+        let dir = std::env::temp_dir().join(format!("oxidebot-storage-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("state.json");
+        let _ = tokio::fs::remove_file(&path).await;
+
+        let storage = JsonStorage::default();
+        assert_eq!(storage.open(path.clone(), "test storage").await, None);
+        for count in 0..3 {
+            storage
+                .update(|| Ok(()), || serde_json::json!({ "count": count }))
+                .await
+                .unwrap();
+        }
+        // a failed change isn't written
+        assert!(storage
+            .update(|| Err(anyhow::anyhow!("no")), || Value::Null)
+            .await
+            .is_err());
+
+        let reopened = JsonStorage::default();
+        assert_eq!(
+            reopened.open(path.clone(), "test storage").await,
+            Some(serde_json::json!({ "count": 2 }))
+        );
+        let mut entries = tokio::fs::read_dir(&dir).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            assert_eq!(entry.path(), path);
+        }
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -5,6 +5,7 @@ use std::{
     str::FromStr,
     time::Duration,
 };
+use tokio::sync::broadcast::{self, error::RecvError};
 
 /// A simple wrapper for bool that can be parsed from string, can be easily used in wait generic
 #[derive(Clone, Copy)]
@@ -40,6 +41,19 @@ impl FromStr for EasyBool {
     }
 }
 
+/// receive the next matcher, the matchers dropped because the waiter lagged behind are skipped
+async fn recv_matcher(receiver: &mut broadcast::Receiver<Matcher>) -> Result<Matcher> {
+    loop {
+        match receiver.recv().await {
+            Ok(matcher) => return Ok(matcher),
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!("Waiter lagged behind, {} events were skipped", skipped);
+            }
+            Err(RecvError::Closed) => return Err(anyhow::anyhow!("Wait error: Channel closed")),
+        }
+    }
+}
+
 /// wait for any matcher that satisfies the filter_fn
 pub async fn wait<F>(
     broadcast_sender: &BroadcastSender,
@@ -51,12 +65,12 @@ where
 {
     let mut receiver = broadcast_sender.subscribe();
     tokio::time::timeout(timeout, async {
-        while let Ok(matcher) = receiver.recv().await {
+        loop {
+            let matcher = recv_matcher(&mut receiver).await?;
             if filter_fn(&matcher) {
                 return Ok(matcher);
             }
         }
-        Err(anyhow::anyhow!("Wait error: Unreachable"))
     })
     .await
     .map_err(|_| anyhow::anyhow!("Wait timed out"))?
@@ -80,34 +94,33 @@ where
     invalid_threshold += 1;
     tokio::time::timeout(timeout, async {
         loop {
-            if let Ok(matcher) = receiver.recv().await {
-                if filter_fn(&matcher) {
-                    if let Some(message) = matcher.try_get_message() {
-                        let text = message.get_raw_text();
-
-                        match text.parse::<T>() {
-                            Ok(value) => return Ok((value, matcher)),
-                            Err(err) => {
-                                invalid_threshold -= 1;
-                                if invalid_threshold == 0 {
-                                    if let Some(error_message) = error_message.clone() {
-                                        matcher
-                                            .try_send_message(vec![MessageSegment::text(format!(
-                                                "{}\nError: {:?}\n\nMax retries exceeded, exited.",
-                                                error_message, err
-                                            ))])
-                                            .await?;
-                                    }
-                                    return Err(anyhow::anyhow!("Max retries exceeded"));
-                                } else {
-                                    if let Some(error_message) = error_message.clone() {
-                                        matcher
-                                            .try_send_message(vec![MessageSegment::text(format!(
-                                                "{error_message}\nError: {:?}",
-                                                err
-                                            ))])
-                                            .await?;
-                                    }
+            let matcher = recv_matcher(&mut receiver).await?;
+            if filter_fn(&matcher) {
+                if let Some(message) = matcher.try_get_message() {
+                    let text = message.get_raw_text();
+
+                    match text.parse::<T>() {
+                        Ok(value) => return Ok((value, matcher)),
+                        Err(err) => {
+                            invalid_threshold -= 1;
+                            if invalid_threshold == 0 {
+                                if let Some(error_message) = error_message.clone() {
+                                    matcher
+                                        .try_send_message(vec![MessageSegment::text(format!(
+                                            "{}\nError: {:?}\n\nMax retries exceeded, exited.",
+                                            error_message, err
+                                        ))])
+                                        .await?;
+                                }
+                                return Err(anyhow::anyhow!("Max retries exceeded"));
+                            } else {
+                                if let Some(error_message) = error_message.clone() {
+                                    matcher
+                                        .try_send_message(vec![MessageSegment::text(format!(
+                                            "{error_message}\nError: {:?}",
+                                            err
+                                        ))])
+                                        .await?;
                                 }
                             }
                         }